@@ -0,0 +1,46 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use crossterm::event::KeyCode;
+
+use crate::performance::parse_key;
+
+/// Something another program asked uauauiua to do, written like a path, such as
+/// `/key/z` or `/record/main/start`, or as words, such as `press z`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    Press(KeyCode),
+    ToggleHold(KeyCode),
+    StopPlayback,
+    StartMainRecording,
+    StopMainRecording,
+    StartSecondaryRecording,
+    StopSecondaryRecording,
+    Reload,
+}
+
+impl ControlCommand {
+    /// Parses a command from its words, such as `["record", "main", "start"]`.
+    pub fn from_words(words: &[&str]) -> anyhow::Result<Self> {
+        Ok(match words {
+            ["key" | "press", key] => ControlCommand::Press(parse_key(key)?),
+            ["hold", key] => ControlCommand::ToggleHold(parse_key(key)?),
+            ["stop"] => ControlCommand::StopPlayback,
+            ["record", "main", "start"] => ControlCommand::StartMainRecording,
+            ["record", "main", "stop"] => ControlCommand::StopMainRecording,
+            ["record", "uiua", "start"] => ControlCommand::StartSecondaryRecording,
+            ["record", "uiua", "stop"] => ControlCommand::StopSecondaryRecording,
+            ["reload"] => ControlCommand::Reload,
+            _ => bail!("unknown command '{}'", words.join(" ")),
+        })
+    }
+}
+
+impl FromStr for ControlCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = s.trim_start_matches('/').split('/').collect();
+        Self::from_words(&words)
+    }
+}
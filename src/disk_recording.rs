@@ -0,0 +1,499 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, ensure};
+use crossterm::event::KeyCode;
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use indexmap::IndexMap;
+
+use crate::export::{ExportOptions, export};
+use crate::performance::{EVENTS_EXTENSION, PerformanceEvent, write_header};
+use crate::recording::{CHANNEL_COUNT, MainRecordingTx, SAMPLE_RATE, StemBlock};
+
+pub const RECORDINGS_DIR: &str = "recordings";
+const TEMP_EXTENSION: &str = "part";
+const RECOVERED_PREFIX: &str = "recovered-";
+// In bytes, since that's how filesystems count. Most allow 255, which leaves room for a
+// `-N` suffix and the longest extension
+const MAX_NAME_LENGTH: usize = 200;
+// Samples between header updates; at most this much is lost if the program crashes
+const FLUSH_INTERVAL: usize = 1 << 16;
+const STEM_FLUSH_INTERVAL: usize = 16;
+// How long finishing waits for the writers before giving up and leaving the temporary
+// files to be recovered on the next start
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+const FINISH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub fn float_wav_spec() -> WavSpec {
+    WavSpec {
+        channels: CHANNEL_COUNT,
+        sample_rate: *SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    }
+}
+
+pub fn timestamp() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros()
+        .to_string()
+}
+
+/// Checks that a recording name can't escape the recordings directory or otherwise
+/// confuse the filesystem, substituting a timestamp if it's empty.
+pub fn resolve_recording_name(name: &str) -> anyhow::Result<String> {
+    if name.is_empty() {
+        return Ok(timestamp());
+    }
+
+    ensure!(
+        name.len() <= MAX_NAME_LENGTH,
+        "recording name must be at most {MAX_NAME_LENGTH} bytes"
+    );
+    ensure!(
+        !name.starts_with('.'),
+        "recording name must not start with '.'"
+    );
+    ensure!(
+        name.trim() == name,
+        "recording name must not start or end with whitespace"
+    );
+    if let Some(c) = name.chars().find(|&c| {
+        c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|')
+    }) {
+        return Err(anyhow!("recording name must not contain {c:?}"));
+    }
+    ensure!(
+        !is_reserved_on_windows(name),
+        "recording name must not be one Windows reserves"
+    );
+
+    Ok(name.to_string())
+}
+
+// Windows won't create files with these names, whatever the extension
+fn is_reserved_on_windows(name: &str) -> bool {
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or(name)
+        .trim_end()
+        .to_ascii_uppercase();
+    match stem.as_bytes() {
+        b"CON" | b"PRN" | b"AUX" | b"NUL" => true,
+        [b'C', b'O', b'M', n] | [b'L', b'P', b'T', n] => matches!(n, b'1'..=b'9'),
+        _ => false,
+    }
+}
+
+pub fn recording_path(name: &str, options: ExportOptions) -> PathBuf {
+    Path::new(RECORDINGS_DIR).join(format!("{name}.{}", options.format.extension()))
+}
+
+fn stems_path(name: &str) -> PathBuf {
+    Path::new(RECORDINGS_DIR).join(format!("{name}-stems"))
+}
+
+pub fn events_path(name: &str) -> PathBuf {
+    Path::new(RECORDINGS_DIR).join(format!("{name}.{EVENTS_EXTENSION}"))
+}
+
+pub fn recording_exists(name: &str, options: ExportOptions) -> bool {
+    recording_path(name, options).exists()
+        || stems_path(name).exists()
+        || events_path(name).exists()
+}
+
+/// Returns the first of `name-1`, `name-2` and so on that isn't taken.
+pub fn next_free_recording_name(name: &str, options: ExportOptions) -> String {
+    (1..)
+        .map(|i| format!("{name}-{i}"))
+        .find(|n| !recording_exists(n, options))
+        .expect("should have found a free recording name")
+}
+
+fn new_temp_stamp() -> anyhow::Result<String> {
+    fs::create_dir_all(RECORDINGS_DIR)?;
+    Ok(timestamp())
+}
+
+fn temp_path(stamp: &str) -> PathBuf {
+    Path::new(RECORDINGS_DIR).join(format!("{stamp}.wav.{TEMP_EXTENSION}"))
+}
+
+fn temp_stems_path(stamp: &str) -> PathBuf {
+    Path::new(RECORDINGS_DIR).join(format!("{stamp}-stems.{TEMP_EXTENSION}"))
+}
+
+fn temp_events_path(stamp: &str) -> PathBuf {
+    Path::new(RECORDINGS_DIR).join(format!("{stamp}.{EVENTS_EXTENSION}.{TEMP_EXTENSION}"))
+}
+
+fn write_mix(
+    mut writer: WavWriter<BufWriter<File>>,
+    sample_rx: Receiver<f32>,
+) -> hound::Result<()> {
+    for (i, sample) in sample_rx.into_iter().enumerate() {
+        writer.write_sample(sample)?;
+        if (i + 1).is_multiple_of(FLUSH_INTERVAL) {
+            writer.flush()?;
+        }
+    }
+    writer.finalize()
+}
+
+fn write_events(path: &Path, event_rx: Receiver<PerformanceEvent>) -> io::Result<()> {
+    let mut writer = LineWriter::new(File::create(path)?);
+    write_header(&mut writer, *SAMPLE_RATE)?;
+    event_rx
+        .into_iter()
+        .try_for_each(|e| writeln!(writer, "{e}"))
+}
+
+/// Writes one file per key, padding with silence so every stem lines up with the mix
+/// no matter when its key was first played.
+fn write_stems(dir: &Path, block_rx: Receiver<StemBlock>) -> hound::Result<()> {
+    let mut writers: IndexMap<KeyCode, WavWriter<BufWriter<File>>> = IndexMap::new();
+    let mut written = 0;
+
+    for (i, block) in block_rx.into_iter().enumerate() {
+        for &key in block.stems.keys() {
+            if !writers.contains_key(&key) {
+                let mut writer =
+                    WavWriter::create(dir.join(format!("{key}.wav")), float_wav_spec())?;
+                (0..written).try_for_each(|_| writer.write_sample(0.0_f32))?;
+                writers.insert(key, writer);
+            }
+        }
+
+        for (key, writer) in &mut writers {
+            match block.stems.get(key) {
+                Some(samples) => samples[..block.len]
+                    .iter()
+                    .try_for_each(|&x| writer.write_sample(x))?,
+                None => (0..block.len).try_for_each(|_| writer.write_sample(0.0_f32))?,
+            }
+            if (i + 1).is_multiple_of(STEM_FLUSH_INTERVAL) {
+                writer.flush()?;
+            }
+        }
+        written += block.len;
+        block.recycle();
+    }
+
+    writers.into_values().try_for_each(WavWriter::finalize)
+}
+
+/// Joins `thread` unless it's still running at `deadline`. `what` names it in errors.
+fn join_by<T>(thread: JoinHandle<T>, deadline: Instant, what: &str) -> anyhow::Result<T> {
+    while !thread.is_finished() {
+        ensure!(
+            Instant::now() < deadline,
+            "timed out waiting for the {what} to finish; the recording will be recovered on the next start"
+        );
+        thread::sleep(FINISH_POLL_INTERVAL);
+    }
+    thread.join().map_err(|_| anyhow!("{what} thread panicked"))
+}
+
+/// A main recording in progress, written to a temporary file as the mixer produces it.
+pub struct DiskRecording {
+    path: PathBuf,
+    stems_path: Option<PathBuf>,
+    events_path: PathBuf,
+    recording_tx: MainRecordingTx,
+    writer_threads: Vec<JoinHandle<hound::Result<()>>>,
+    events_thread: JoinHandle<io::Result<()>>,
+}
+
+impl DiskRecording {
+    pub fn start(record_stems: bool) -> anyhow::Result<Self> {
+        let stamp = new_temp_stamp()?;
+        let path = temp_path(&stamp);
+        let writer = WavWriter::create(&path, float_wav_spec())?;
+        let (mix_tx, mix_rx) = channel();
+        let mut writer_threads = vec![thread::spawn(move || write_mix(writer, mix_rx))];
+
+        let events_path = temp_events_path(&stamp);
+        let (events_tx, events_rx) = channel();
+        let thread_events_path = events_path.clone();
+        let events_thread = thread::spawn(move || write_events(&thread_events_path, events_rx));
+
+        let (stems_path, stems_tx) = if record_stems {
+            let dir = temp_stems_path(&stamp);
+            fs::create_dir(&dir)?;
+            let (stems_tx, stems_rx) = channel();
+            let thread_dir = dir.clone();
+            writer_threads.push(thread::spawn(move || write_stems(&thread_dir, stems_rx)));
+            (Some(dir), Some(stems_tx))
+        } else {
+            (None, None)
+        };
+
+        Ok(DiskRecording {
+            path,
+            stems_path,
+            events_path,
+            recording_tx: MainRecordingTx {
+                mix: mix_tx,
+                stems: stems_tx,
+                events: events_tx,
+                frames: Arc::new(AtomicU64::new(0)),
+            },
+            writer_threads,
+            events_thread,
+        })
+    }
+
+    /// Returns senders for the mixer to write to. The recording only ends once every
+    /// sender has been dropped.
+    pub fn sender(&self) -> MainRecordingTx {
+        self.recording_tx.clone()
+    }
+
+    /// How many frames the mixer has recorded so far.
+    pub fn frames(&self) -> u64 {
+        self.recording_tx.frames.load(Ordering::Relaxed)
+    }
+
+    /// How big the mix file is so far. Lags behind `frames` while the writer catches up.
+    pub fn file_size(&self) -> u64 {
+        fs::metadata(&self.path).map_or(0, |m| m.len())
+    }
+
+    /// Waits for the mixer to let go of the recording, then finalizes the files. Gives up
+    /// after a while if the mixer has stalled, rather than hang the interface.
+    pub fn finish(self) -> anyhow::Result<Take> {
+        drop(self.recording_tx);
+        let deadline = Instant::now() + FINISH_TIMEOUT;
+        for thread in self.writer_threads {
+            join_by(thread, deadline, "recording writer")??;
+        }
+        join_by(self.events_thread, deadline, "event log writer")??;
+        Ok(Take {
+            path: self.path,
+            stems_path: self.stems_path,
+            events_path: Some(self.events_path),
+        })
+    }
+}
+
+/// A finished main recording waiting to be named.
+pub struct Take {
+    path: PathBuf,
+    stems_path: Option<PathBuf>,
+    events_path: Option<PathBuf>,
+}
+
+impl Take {
+    pub fn from_samples(samples: &[f32]) -> anyhow::Result<Self> {
+        let path = temp_path(&new_temp_stamp()?);
+        let mut writer = WavWriter::create(&path, float_wav_spec())?;
+        samples.iter().try_for_each(|&x| writer.write_sample(x))?;
+        writer.finalize()?;
+        Ok(Take {
+            path,
+            stems_path: None,
+            events_path: None,
+        })
+    }
+
+    /// Saves the take under an already resolved name, replacing any existing files. If
+    /// that fails, the take is left as it was, so that it can be saved again.
+    pub fn save(&self, name: &str, options: ExportOptions) -> anyhow::Result<()> {
+        // Stems stay as float WAVs since they're meant for further editing
+        let new_stems_path = stems_path(name);
+        if new_stems_path.exists() {
+            fs::remove_dir_all(&new_stems_path)?;
+        }
+        let new_events_path = events_path(name);
+        if self.events_path.is_none() && new_events_path.exists() {
+            fs::remove_file(&new_events_path)?;
+        }
+
+        // The mix goes last, since it's what marks a saved take, and anything moved before
+        // it is moved back if something fails
+        let mut moved = Vec::new();
+        let companions = [
+            (&self.stems_path, new_stems_path),
+            (&self.events_path, new_events_path),
+        ];
+        for (from, to) in companions {
+            let Some(from) = from else {
+                continue;
+            };
+            if let Err(e) = fs::rename(from, &to) {
+                undo_renames(&moved);
+                return Err(e.into());
+            }
+            moved.push((from.as_path(), to));
+        }
+        self.save_mix(name, options)
+            .inspect_err(|_| undo_renames(&moved))
+    }
+
+    fn save_mix(&self, name: &str, options: ExportOptions) -> anyhow::Result<()> {
+        let path = recording_path(name, options);
+        if options.is_passthrough() {
+            fs::rename(&self.path, path)?;
+        } else {
+            export(&self.path, &path, options)?;
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn discard(&self) -> anyhow::Result<()> {
+        fs::remove_file(&self.path)?;
+        if let Some(p) = &self.stems_path {
+            fs::remove_dir_all(p)?;
+        }
+        if let Some(p) = &self.events_path {
+            fs::remove_file(p)?;
+        }
+        Ok(())
+    }
+}
+
+// Best effort, since this only runs when saving has already gone wrong
+fn undo_renames(renames: &[(&Path, PathBuf)]) {
+    for (from, to) in renames.iter().rev() {
+        let _ = fs::rename(to, from);
+    }
+}
+
+/// Renames temporary files left behind by an unclean exit so they show up as
+/// regular recordings. Returns the paths of the recovered recordings.
+pub fn recover_orphaned_recordings() -> anyhow::Result<Vec<PathBuf>> {
+    let Ok(entries) = fs::read_dir(RECORDINGS_DIR) else {
+        return Ok(Vec::new());
+    };
+
+    let mut recovered = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|e| e != TEMP_EXTENSION) {
+            continue;
+        }
+        // The header is only as up to date as the last flush, but that's still a
+        // readable file
+        let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        if stem.ends_with(".wav") && WavReader::open(&path).is_err() {
+            continue;
+        }
+        let new_path = Path::new(RECORDINGS_DIR).join(format!("{RECOVERED_PREFIX}{stem}"));
+        fs::rename(&path, &new_path)?;
+        recovered.push(new_path);
+    }
+    Ok(recovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn accepts_ordinary_names() {
+        for name in [
+            "take",
+            "jam 2",
+            "köln-ünd-so",
+            "a.b",
+            "x".repeat(MAX_NAME_LENGTH).as_str(),
+            "console",
+            "com0",
+            "lpt10",
+            "nul-1",
+        ] {
+            assert_eq!(resolve_recording_name(name).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn rejects_names_that_escape_or_confuse() {
+        for name in [
+            "..",
+            "../take",
+            ".hidden",
+            "a/b",
+            "a\\b",
+            "c:take",
+            "tab\there",
+            "new\nline",
+            "nul\0",
+            " padded",
+            "padded ",
+            &"x".repeat(MAX_NAME_LENGTH + 1),
+            // Few enough characters, but too many bytes
+            &"ö".repeat(MAX_NAME_LENGTH / 2 + 1),
+            "con",
+            "NUL",
+            "Aux.take",
+            "com1",
+            "LPT9",
+        ] {
+            assert!(resolve_recording_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn failed_save_leaves_the_take_whole() {
+        let dir = env::temp_dir().join(format!("uauauiua-save-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("take-stems.part")).unwrap();
+        let take = Take {
+            path: dir.join("take.wav.part"),
+            stems_path: Some(dir.join("take-stems.part")),
+            events_path: Some(dir.join("take.events.part")),
+        };
+        fs::write(&take.path, "mix").unwrap();
+        fs::write(take.events_path.as_ref().unwrap(), "events").unwrap();
+
+        // Names are joined onto the recordings folder, so an absolute one keeps this out
+        // of it. A folder in the mix's way makes its rename fail
+        let name = dir.join("saved");
+        let name = name.to_str().unwrap();
+        let options = ExportOptions::default();
+        fs::create_dir_all(recording_path(name, options).join("in the way")).unwrap();
+        assert!(take.save(name, options).is_err());
+        for path in [
+            &take.path,
+            &dir.join("take-stems.part"),
+            &dir.join("take.events.part"),
+        ] {
+            assert!(path.exists(), "{}", path.display());
+        }
+        assert!(!stems_path(name).exists());
+        assert!(!events_path(name).exists());
+
+        fs::remove_dir_all(recording_path(name, options)).unwrap();
+        take.save(name, options).unwrap();
+        assert!(!take.path.exists());
+        assert_eq!(fs::read_to_string(events_path(name)).unwrap(), "events");
+        assert!(stems_path(name).is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_names_become_timestamps() {
+        let name = resolve_recording_name("").unwrap();
+        assert!(!name.is_empty());
+        assert!(name.chars().all(|c| c.is_ascii_digit()), "{name}");
+    }
+}
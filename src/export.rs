@@ -0,0 +1,308 @@
+use std::{fmt, ops::Range, path::Path};
+
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+
+use crate::disk_recording::float_wav_spec;
+use crate::flac::FlacWriter;
+use crate::recording::{CHANNEL_COUNT, SAMPLE_RATE};
+
+// About -60 dBFS
+const SILENCE_THRESHOLD: f32 = 0.001;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    Pcm16,
+    Pcm24,
+    #[default]
+    Float32,
+    Flac24,
+}
+
+impl ExportFormat {
+    pub fn next(self) -> Self {
+        match self {
+            ExportFormat::Pcm16 => ExportFormat::Pcm24,
+            ExportFormat::Pcm24 => ExportFormat::Float32,
+            ExportFormat::Float32 => ExportFormat::Flac24,
+            ExportFormat::Flac24 => ExportFormat::Pcm16,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Pcm16 | ExportFormat::Pcm24 | ExportFormat::Float32 => "wav",
+            ExportFormat::Flac24 => "flac",
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExportFormat::Pcm16 => "16-bit WAV",
+            ExportFormat::Pcm24 => "24-bit WAV",
+            ExportFormat::Float32 => "32-bit float WAV",
+            ExportFormat::Flac24 => "24-bit FLAC",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub normalize: bool,
+    pub trim_silence: bool,
+}
+
+impl ExportOptions {
+    /// Whether the recording can be saved exactly as the mixer wrote it.
+    pub fn is_passthrough(self) -> bool {
+        self == ExportOptions::default()
+    }
+}
+
+/// Small xorshift generator; dither doesn't need anything better.
+struct DitherRng(u32);
+
+impl DitherRng {
+    fn next_unit(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        // The top 24 bits fit exactly in an f32
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+
+    /// Triangular noise spanning plus or minus one step.
+    fn tpdf(&mut self) -> f32 {
+        self.next_unit() - self.next_unit()
+    }
+}
+
+/// What one pass over a recording finds out, so that the next can stream it straight to
+/// the exported file.
+#[derive(Default)]
+struct Levels {
+    peak: f32,
+    samples: usize,
+    loud_frames: Option<(usize, usize)>,
+}
+
+impl Levels {
+    fn add(&mut self, x: f32) {
+        let frame = self.samples / CHANNEL_COUNT as usize;
+        self.peak = self.peak.max(x.abs());
+        self.samples += 1;
+        if x.abs() > SILENCE_THRESHOLD {
+            let first = self.loud_frames.map_or(frame, |(first, _)| first);
+            self.loud_frames = Some((first, frame));
+        }
+    }
+
+    /// The samples left once silence is trimmed off each end, which is none at all if
+    /// the recording is silent.
+    fn trim_silence(&self) -> Range<usize> {
+        let channels = CHANNEL_COUNT as usize;
+        self.loud_frames.map_or(0..0, |(first, last)| {
+            first * channels..(last + 1) * channels
+        })
+    }
+
+    fn untrimmed(&self) -> Range<usize> {
+        0..self.samples - self.samples % CHANNEL_COUNT as usize
+    }
+
+    /// The gain that brings the peak up to full scale.
+    fn normalize(&self) -> f32 {
+        if self.peak > 0.0 {
+            self.peak.recip()
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Turns samples into integers, with dither that carries on from one sample to the next.
+struct Quantizer {
+    scale: f32,
+    rng: DitherRng,
+}
+
+impl Quantizer {
+    fn new(bits_per_sample: u32) -> Self {
+        Quantizer {
+            scale: ((1 << (bits_per_sample - 1)) - 1) as f32,
+            rng: DitherRng(0x9E37_79B9),
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn quantize(&mut self, x: f32) -> i32 {
+        (x * self.scale + self.rng.tpdf())
+            .round()
+            .clamp(-self.scale - 1.0, self.scale) as i32
+    }
+}
+
+fn write_wav_int(
+    path: &Path,
+    samples: impl Iterator<Item = hound::Result<f32>>,
+    bits_per_sample: u16,
+) -> anyhow::Result<()> {
+    let spec = WavSpec {
+        channels: CHANNEL_COUNT,
+        sample_rate: *SAMPLE_RATE,
+        bits_per_sample,
+        sample_format: SampleFormat::Int,
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+    let mut quantizer = Quantizer::new(bits_per_sample.into());
+    for x in samples {
+        writer.write_sample(quantizer.quantize(x?))?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+fn write_wav_float(
+    path: &Path,
+    samples: impl Iterator<Item = hound::Result<f32>>,
+) -> anyhow::Result<()> {
+    let mut writer = WavWriter::create(path, float_wav_spec())?;
+    for x in samples {
+        writer.write_sample(x?)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+fn write_flac24(
+    path: &Path,
+    samples: impl Iterator<Item = hound::Result<f32>>,
+) -> anyhow::Result<()> {
+    let mut writer = FlacWriter::create(path, CHANNEL_COUNT, *SAMPLE_RATE, 24)?;
+    let mut quantizer = Quantizer::new(24);
+    for x in samples {
+        writer.write_sample(quantizer.quantize(x?))?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Exports the float WAV at `source` to `path`. The source is read twice, once to
+/// measure it and once to convert it, so it never has to fit in memory.
+pub fn export(source: &Path, path: &Path, options: ExportOptions) -> anyhow::Result<()> {
+    let mut levels = Levels::default();
+    for x in WavReader::open(source)?.into_samples::<f32>() {
+        levels.add(x?);
+    }
+
+    let range = if options.trim_silence {
+        levels.trim_silence()
+    } else {
+        levels.untrimmed()
+    };
+    let gain = if options.normalize {
+        levels.normalize()
+    } else {
+        1.0
+    };
+    let samples = WavReader::open(source)?
+        .into_samples::<f32>()
+        .skip(range.start)
+        .take(range.len())
+        .map(|x| x.map(|x| x * gain));
+
+    match options.format {
+        ExportFormat::Pcm16 => write_wav_int(path, samples, 16),
+        ExportFormat::Pcm24 => write_wav_int(path, samples, 24),
+        ExportFormat::Float32 => write_wav_float(path, samples),
+        ExportFormat::Flac24 => write_flac24(path, samples),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    fn measure(samples: &[f32]) -> Levels {
+        let mut levels = Levels::default();
+        samples.iter().for_each(|&x| levels.add(x));
+        levels
+    }
+
+    #[test]
+    fn trims_to_the_loud_frames() {
+        let levels = measure(&[0.0, 0.0, 0.0, 0.5, 0.2, 0.0, 0.0, 0.0]);
+        assert_eq!(levels.trim_silence(), 2..6);
+        assert_eq!(levels.untrimmed(), 0..8);
+    }
+
+    #[test]
+    fn trims_silence_to_nothing() {
+        let levels = measure(&[0.0, SILENCE_THRESHOLD, -SILENCE_THRESHOLD, 0.0]);
+        assert!(levels.trim_silence().is_empty());
+    }
+
+    #[test]
+    fn leaves_empty_takes_alone() {
+        let levels = measure(&[]);
+        assert!(levels.trim_silence().is_empty());
+        assert!(levels.untrimmed().is_empty());
+        assert_eq!(levels.normalize(), 1.0);
+    }
+
+    #[test]
+    fn normalizes_to_full_scale() {
+        assert_eq!(measure(&[0.25, -0.5]).normalize(), 2.0);
+        assert_eq!(measure(&[0.0, 0.0]).normalize(), 1.0);
+        assert_eq!(measure(&[1.5, -3.0]).normalize(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn quantizing_clips_at_full_scale() {
+        let mut quantizer = Quantizer::new(16);
+        for _ in 0..100 {
+            assert_eq!(quantizer.quantize(2.0), i32::from(i16::MAX));
+            assert_eq!(quantizer.quantize(-2.0), i32::from(i16::MIN));
+            let x = quantizer.quantize(1.0);
+            assert!((i32::from(i16::MAX) - 1..=i32::from(i16::MAX)).contains(&x));
+            assert!((-1..=1).contains(&quantizer.quantize(0.0)));
+        }
+    }
+
+    #[test]
+    fn exports_an_empty_take() {
+        let dir = env::temp_dir().join(format!("uauauiua-export-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("take.wav");
+        WavWriter::create(&source, float_wav_spec())
+            .unwrap()
+            .finalize()
+            .unwrap();
+
+        for format in [
+            ExportFormat::Pcm16,
+            ExportFormat::Pcm24,
+            ExportFormat::Float32,
+            ExportFormat::Flac24,
+        ] {
+            let options = ExportOptions {
+                format,
+                normalize: true,
+                trim_silence: true,
+            };
+            let path = dir.join(format!("exported.{}", format.extension()));
+            export(&source, &path, options).unwrap();
+            let len = if format == ExportFormat::Flac24 {
+                claxon::FlacReader::open(&path).unwrap().samples().count()
+            } else {
+                WavReader::open(&path).unwrap().len() as usize
+            };
+            assert_eq!(len, 0, "{format}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::anyhow;
+use flacenc::{
+    bitsink::MemSink,
+    component::{BitRepr, Stream, StreamInfo},
+    config,
+    error::{Verified, Verify},
+    source::{Context, Fill, FrameBuf},
+};
+
+const BLOCK_SIZE: usize = 4096;
+
+/// Writes interleaved integer samples to a FLAC file a block at a time, so that the
+/// whole recording never has to be in memory. The header is filled in once the last
+/// block is written.
+pub struct FlacWriter {
+    file: BufWriter<File>,
+    config: Verified<config::Encoder>,
+    stream_info: StreamInfo,
+    channel_count: usize,
+    block: Vec<i32>,
+    frame_buf: FrameBuf,
+    context: Context,
+}
+
+// "fLaC" and the STREAMINFO block, which are always the same size, so the header can
+// be written over once the stream's finished
+fn write_header(file: &mut impl Write, stream_info: &StreamInfo) -> anyhow::Result<()> {
+    let mut sink = MemSink::<u8>::new();
+    Stream::with_stream_info(stream_info.clone())
+        .write(&mut sink)
+        .map_err(|e| anyhow!("could not write FLAC header: {e}"))?;
+    file.write_all(sink.as_slice())?;
+    Ok(())
+}
+
+impl FlacWriter {
+    /// Fails if FLAC or the encoder doesn't support the format, such as a bit depth over
+    /// 24 or a sample rate over 96 kHz.
+    pub fn create(
+        path: &Path,
+        channel_count: u16,
+        sample_rate: u32,
+        bits_per_sample: u32,
+    ) -> anyhow::Result<Self> {
+        let channel_count = usize::from(channel_count);
+        let bits = usize::try_from(bits_per_sample)?;
+        let mut stream_info = StreamInfo::new(usize::try_from(sample_rate)?, channel_count, bits)
+            .map_err(|e| anyhow!("can't write this as FLAC: {e}"))?;
+        stream_info.set_block_sizes(BLOCK_SIZE, BLOCK_SIZE)?;
+        let config = config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, e)| anyhow!("invalid FLAC encoder settings: {e}"))?;
+
+        let mut file = BufWriter::new(File::create(path)?);
+        write_header(&mut file, &stream_info)?;
+        Ok(FlacWriter {
+            file,
+            config,
+            stream_info,
+            channel_count,
+            block: Vec::with_capacity(BLOCK_SIZE * channel_count),
+            frame_buf: FrameBuf::with_size(channel_count, BLOCK_SIZE)?,
+            context: Context::new(bits, channel_count),
+        })
+    }
+
+    pub fn write_sample(&mut self, sample: i32) -> anyhow::Result<()> {
+        self.block.push(sample);
+        if self.block.len() == BLOCK_SIZE * self.channel_count {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> anyhow::Result<()> {
+        // A partial frame at the end can't be encoded, so it's dropped
+        let len = self.block.len() - self.block.len() % self.channel_count;
+        if len > 0 {
+            (&mut self.frame_buf, &mut self.context)
+                .fill_interleaved(&self.block[..len])
+                .map_err(|e| anyhow!("could not read samples into a FLAC frame: {e}"))?;
+            let frame_number = self
+                .context
+                .current_frame_number()
+                .expect("a block should have just been read");
+            let frame = flacenc::encode_fixed_size_frame(
+                &self.config,
+                &self.frame_buf,
+                frame_number,
+                &self.stream_info,
+            )
+            .map_err(|e| anyhow!("could not encode FLAC frame: {e}"))?;
+            self.stream_info.update_frame_info(&frame);
+
+            let mut sink = MemSink::<u8>::with_capacity(frame.count_bits());
+            frame
+                .write(&mut sink)
+                .map_err(|e| anyhow!("could not write FLAC frame: {e}"))?;
+            self.file.write_all(sink.as_slice())?;
+        }
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes out whatever is left of the last block and fills in the header.
+    pub fn finalize(mut self) -> anyhow::Result<()> {
+        self.write_block()?;
+        self.stream_info.set_md5_digest(&self.context.md5_digest());
+        self.file.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.file, &self.stream_info)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let path = env::temp_dir().join(format!("uauauiua-flac-{}.flac", process::id()));
+        // A bit over two blocks, so there's a short one at the end, with a ramp in one
+        // channel and full scale noise in the other
+        let frames = BLOCK_SIZE * 2 + 100;
+        let mut noise = 1_u32;
+        let samples: Vec<i32> = (0..frames)
+            .flat_map(|i| {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let ramp = i32::try_from(i).unwrap() - 5000;
+                [ramp, noise.cast_signed() >> 8]
+            })
+            .collect();
+
+        let mut writer = FlacWriter::create(&path, 2, 44_100, 24).unwrap();
+        samples
+            .iter()
+            .try_for_each(|&x| writer.write_sample(x))
+            .unwrap();
+        writer.finalize().unwrap();
+
+        let mut reader = claxon::FlacReader::open(&path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(
+            (info.channels, info.sample_rate, info.bits_per_sample),
+            (2, 44_100, 24)
+        );
+        assert_eq!(info.samples, Some(frames as u64));
+        let decoded: Vec<i32> = reader.samples().map(Result::unwrap).collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn writes_empty_files() {
+        let path = env::temp_dir().join(format!("uauauiua-flac-empty-{}.flac", process::id()));
+        FlacWriter::create(&path, 2, 44_100, 24)
+            .unwrap()
+            .finalize()
+            .unwrap();
+        let mut reader = claxon::FlacReader::open(&path).unwrap();
+        // FLAC has no way to say zero samples, only that the count is unknown
+        assert_eq!(reader.streaminfo().samples, None);
+        assert_eq!(reader.samples().count(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_unsupported_formats() {
+        let path = env::temp_dir().join(format!("uauauiua-flac-bad-{}.flac", process::id()));
+        assert!(FlacWriter::create(&path, 2, 44_100, 32).is_err());
+        assert!(FlacWriter::create(&path, 2, 384_000, 24).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+
+use crossterm::event::KeyCode;
+use indexmap::IndexSet;
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Style, Stylize},
+    text::Line,
+    widgets::Widget,
+};
+
+/// Rows in the layout, and so the height of the keyboard
+pub const KEYBOARD_ROWS: u16 = 6;
+// Each row is indented by this many cells, roughly like a real keyboard
+const ROW_INDENTS: [u16; KEYBOARD_ROWS as usize] = [0, 0, 0, 2, 4, 8];
+
+const FUNCTION_ROW: &[KeyCode] = &[
+    KeyCode::Esc,
+    KeyCode::F(1),
+    KeyCode::F(2),
+    KeyCode::F(3),
+    KeyCode::F(4),
+    KeyCode::F(5),
+    KeyCode::F(6),
+    KeyCode::F(7),
+    KeyCode::F(8),
+    KeyCode::F(9),
+    KeyCode::F(10),
+    KeyCode::F(11),
+    KeyCode::F(12),
+];
+const NAVIGATION_ROW: &[KeyCode] = &[
+    KeyCode::Char(' '),
+    KeyCode::Delete,
+    KeyCode::Home,
+    KeyCode::End,
+    KeyCode::PageUp,
+    KeyCode::PageDown,
+    KeyCode::Up,
+    KeyCode::Down,
+];
+
+/// The keys of each row of a QWERTY keyboard, top to bottom.
+fn layout() -> [Vec<KeyCode>; KEYBOARD_ROWS as usize] {
+    let chars = |s: &str| s.chars().map(KeyCode::Char).collect::<Vec<_>>();
+    [
+        FUNCTION_ROW.to_vec(),
+        [chars("`1234567890-="), vec![KeyCode::Backspace]].concat(),
+        [vec![KeyCode::Tab], chars("qwertyuiop[]\\")].concat(),
+        [chars("asdfghjkl;'"), vec![KeyCode::Enter]].concat(),
+        chars("zxcvbnm,./"),
+        NAVIGATION_ROW.to_vec(),
+    ]
+}
+
+fn label(key: KeyCode) -> String {
+    match key {
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Backspace => "Bksp".to_string(),
+        KeyCode::Delete => "Del".to_string(),
+        KeyCode::PageUp => "PgUp".to_string(),
+        KeyCode::PageDown => "PgDn".to_string(),
+        KeyCode::Up => "↑".to_string(),
+        KeyCode::Down => "↓".to_string(),
+        key => key.to_string(),
+    }
+}
+
+/// The keys in `keys` that the on-screen keyboard doesn't have.
+pub fn off_layout(keys: &IndexSet<KeyCode>) -> IndexSet<KeyCode> {
+    let layout = layout();
+    keys.iter()
+        .filter(|k| !layout.iter().flatten().any(|l| l == *k))
+        .copied()
+        .collect()
+}
+
+/// A QWERTY keyboard with the keys that play sounds, the held ones, the ones the
+/// interface uses and the ones that were just triggered picked out.
+pub struct KeyboardView<'a> {
+    pub mapped: &'a IndexSet<KeyCode>,
+    pub held: &'a IndexSet<KeyCode>,
+    pub reserved: &'a [KeyCode],
+    pub flashing: &'a HashSet<KeyCode>,
+}
+
+impl KeyboardView<'_> {
+    // Reserved keys never reach the mixer, so they win over being mapped
+    fn style(&self, key: KeyCode) -> Style {
+        if self.flashing.contains(&key) {
+            Style::new().black().on_white().bold()
+        } else if self.reserved.contains(&key) {
+            Style::new().magenta()
+        } else if self.held.contains(&key) {
+            Style::new().black().on_yellow().bold()
+        } else if self.mapped.contains(&key) {
+            Style::new().black().on_green()
+        } else {
+            Style::new().dark_gray()
+        }
+    }
+
+    /// A line explaining the styles, drawn in them.
+    pub fn legend() -> Line<'static> {
+        Line::from(vec![
+            " mapped ".black().on_green(),
+            " ".into(),
+            " held ".black().on_yellow().bold(),
+            " ".into(),
+            " controls ".magenta(),
+            " ".into(),
+            " unused ".dark_gray(),
+        ])
+    }
+}
+
+impl Widget for KeyboardView<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        for ((row, indent), y) in layout()
+            .into_iter()
+            .zip(ROW_INDENTS)
+            .zip(area.top()..area.bottom())
+        {
+            let mut x = area.left() + indent;
+            for key in row {
+                let text = format!(" {} ", label(key));
+                let Ok(width) = u16::try_from(text.chars().count()) else {
+                    continue;
+                };
+                if x + width > area.right() {
+                    break;
+                }
+                buf.set_string(x, y, text, self.style(key));
+                x += width + 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_keys_missing_from_the_layout() {
+        let keys: IndexSet<KeyCode> = "a!z ".chars().map(KeyCode::Char).collect();
+        assert_eq!(off_layout(&keys), IndexSet::from([KeyCode::Char('!')]));
+    }
+}
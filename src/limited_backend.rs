@@ -1,9 +1,201 @@
 use std::{
     any::Any,
+    collections::VecDeque,
+    env, fs,
+    io::{self, Cursor},
+    mem,
     path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, Sender, SyncSender, TryRecvError, channel, sync_channel},
+    },
+    thread,
 };
 
-use uiua::{GitTarget, Handle, NativeSys, ReadLinesReturnFn, SysBackend};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use rodio::{Decoder, buffer::SamplesBuffer, source::UniformSourceIterator};
+use uiua::{AudioStreamFn, GitTarget, Handle, NativeSys, ReadLinesReturnFn, SysBackend};
+
+use crate::disk_recording::RECORDINGS_DIR;
+use crate::recording::{CHANNEL_COUNT, MixerCommand, SAMPLE_RATE};
+use crate::uiua_extension::MAIN_PATH;
+
+const STREAM_BLOCK_FRAMES: u64 = 1024;
+// Blocks an `&ast` function can get ahead of playback by
+const QUEUED_STREAM_BLOCKS: usize = 4;
+// Enough buffers for a full queue, the block being played and the one being generated
+const STREAM_BLOCK_BUFFERS: usize = QUEUED_STREAM_BLOCKS + 2;
+const MAX_LOG_LINES: usize = 1000;
+const MAX_SHOWN_IMAGES: usize = 4;
+const GIT_DIR: &str = ".git";
+
+/// Asks for a line of input, which is sent back through the included sender. `None` is
+/// the end of input.
+pub type LineRequest = Sender<Option<String>>;
+
+/// Who `&sc` asks for lines of input. Empty unless main.ua is being run somewhere that
+/// can ask the user.
+pub type InputConnection = Arc<Mutex<Option<Sender<LineRequest>>>>;
+
+/// Where `&ap` and `&ast` send their sounds, which is replaced whenever audio is
+/// reinitialized. Empty until a mixer is connected.
+pub type MixerConnection = Arc<Mutex<Option<Sender<MixerCommand>>>>;
+
+/// An image main.ua showed with `&ims` or `&gif`. Only the first frame of a GIF is kept.
+pub struct ShownImage {
+    pub label: Option<String>,
+    pub image: RgbaImage,
+}
+
+/// What the backend shares with the rest of uauauiua.
+#[derive(Clone, Default)]
+pub struct SharedState {
+    pub mixer: MixerConnection,
+    pub log: Arc<Mutex<OutputLog>>,
+    pub input: InputConnection,
+    /// The latest `MAX_SHOWN_IMAGES` images shown
+    pub images: Arc<Mutex<VecDeque<ShownImage>>>,
+}
+
+/// A line main.ua printed.
+pub struct LogLine {
+    pub text: String,
+    pub is_stderr: bool,
+}
+
+/// What main.ua has printed, dropping the oldest lines past `MAX_LOG_LINES`.
+#[derive(Default)]
+pub struct OutputLog {
+    lines: VecDeque<LogLine>,
+    // Whether the last line is still waiting for its newline
+    line_open: bool,
+}
+
+impl OutputLog {
+    fn push_str(&mut self, s: &str, is_stderr: bool) {
+        let mut parts = s.split('\n').peekable();
+        while let Some(part) = parts.next() {
+            let ends_line = parts.peek().is_some();
+            if part.is_empty() && !ends_line {
+                break;
+            }
+            match self.lines.back_mut() {
+                Some(line) if self.line_open && line.is_stderr == is_stderr => {
+                    line.text.push_str(part);
+                }
+                _ => {
+                    if self.lines.len() == MAX_LOG_LINES {
+                        self.lines.pop_front();
+                    }
+                    self.lines.push_back(LogLine {
+                        text: part.to_string(),
+                        is_stderr,
+                    });
+                }
+            }
+            self.line_open = !ends_line;
+        }
+    }
+
+    pub fn lines(&self) -> &VecDeque<LogLine> {
+        &self.lines
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.line_open = false;
+    }
+}
+
+/// An `&ast` function, which runs on a thread of its own so that Uiua never holds up the
+/// mixer. Plays silence whenever the function falls behind, and ends once it fails or
+/// returns no frames.
+struct AudioStream {
+    blocks: Receiver<Vec<f32>>,
+    recycle_tx: SyncSender<Vec<f32>>,
+    block: Vec<f32>,
+    position: usize,
+    // Samples left in a frame of silence, so that the channels stay in step
+    silence: usize,
+}
+
+impl AudioStream {
+    fn spawn(f: AudioStreamFn) -> Self {
+        let (tx, blocks) = sync_channel(QUEUED_STREAM_BLOCKS);
+        let (recycle_tx, recycled) = sync_channel(STREAM_BLOCK_BUFFERS);
+        let capacity = usize::try_from(STREAM_BLOCK_FRAMES).expect("block should fit in memory")
+            * usize::from(CHANNEL_COUNT);
+        for _ in 0..STREAM_BLOCK_BUFFERS {
+            let _ = recycle_tx.try_send(Vec::with_capacity(capacity));
+        }
+        thread::spawn(move || generate(f, &tx, &recycled));
+        AudioStream {
+            blocks,
+            recycle_tx,
+            block: Vec::new(),
+            position: 0,
+            silence: 0,
+        }
+    }
+}
+
+impl Iterator for AudioStream {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.silence > 0 {
+            self.silence -= 1;
+            return Some(0.0);
+        }
+        if let Some(&x) = self.block.get(self.position) {
+            self.position += 1;
+            return Some(x);
+        }
+
+        match self.blocks.try_recv() {
+            Ok(block) => {
+                let played = mem::replace(&mut self.block, block);
+                // Only fails for the empty block the stream starts with
+                let _ = self.recycle_tx.try_send(played);
+                self.position = 1;
+                self.block.first().copied()
+            }
+            Err(TryRecvError::Empty) => {
+                self.silence = usize::from(CHANNEL_COUNT) - 1;
+                Some(0.0)
+            }
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+/// Calls an `&ast` function with the times of each block of frames in turn, filling
+/// recycled buffers with what it returns. Stops once the function fails or returns
+/// nothing, or the stream is dropped.
+fn generate(mut f: AudioStreamFn, blocks: &SyncSender<Vec<f32>>, recycled: &Receiver<Vec<f32>>) {
+    let mut times = Vec::new();
+    let mut next_frame = 0;
+    while let Ok(mut block) = recycled.recv() {
+        let frames = next_frame..next_frame + STREAM_BLOCK_FRAMES;
+        next_frame = frames.end;
+        times.clear();
+        #[allow(clippy::cast_precision_loss)]
+        times.extend(frames.map(|i| i as f64 / f64::from(*SAMPLE_RATE)));
+        let Ok(samples) = f(&times) else {
+            return;
+        };
+        if samples.is_empty() {
+            return;
+        }
+
+        block.clear();
+        #[allow(clippy::cast_possible_truncation)]
+        block.extend(samples.into_iter().flat_map(|[l, r]| [l as f32, r as f32]));
+        if blocks.send(block).is_err() {
+            return;
+        }
+    }
+}
 
 macro_rules! native_call_methods {
     ($($name:ident($($arg:ident: $arg_type:ty),*) -> $ret_type:ty;)+) => {
@@ -13,7 +205,137 @@ macro_rules! native_call_methods {
 	};
 }
 
-pub struct LimitedBackend;
+/// Makes a path absolute and resolves any symlinks in it, even if the file it names
+/// doesn't exist yet.
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    let absolute = env::current_dir()?.join(path);
+    let mut existing = absolute.as_path();
+    let mut missing = Vec::new();
+    // Symlink metadata so that a dangling symlink counts as existing and fails to
+    // canonicalize rather than being written through
+    while fs::symlink_metadata(existing).is_err() {
+        let (Some(name), Some(parent)) = (existing.file_name(), existing.parent()) else {
+            return Err(io::Error::other("path could not be resolved"));
+        };
+        missing.push(name);
+        existing = parent;
+    }
+
+    let mut resolved = existing.canonicalize()?;
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
+}
+
+/// Passes calls through to `NativeSys`, except that files can only be changed inside
+/// the project and recordings directories, the working directory can't be changed and
+/// git modules can only be loaded if allowed. Audio is played through the mixer.
+pub struct LimitedBackend {
+    writable_dirs: Vec<PathBuf>,
+    // Can't be deleted, along with everything in them and the writable directories
+    protected_paths: Vec<PathBuf>,
+    allow_git: bool,
+    shared: SharedState,
+}
+
+impl LimitedBackend {
+    pub fn new(allow_git: bool, shared: SharedState) -> Self {
+        Self::with_writable_dirs(
+            vec![PathBuf::from("."), PathBuf::from(RECORDINGS_DIR)],
+            vec![PathBuf::from(MAIN_PATH), PathBuf::from(GIT_DIR)],
+            allow_git,
+            shared,
+        )
+    }
+
+    fn with_writable_dirs(
+        writable_dirs: Vec<PathBuf>,
+        protected_paths: Vec<PathBuf>,
+        allow_git: bool,
+        shared: SharedState,
+    ) -> Self {
+        LimitedBackend {
+            writable_dirs,
+            protected_paths,
+            allow_git,
+            shared,
+        }
+    }
+
+    fn log(&self, s: &str, is_stderr: bool) {
+        self.shared
+            .log
+            .lock()
+            .expect("output log should not be poisoned")
+            .push_str(s, is_stderr);
+    }
+
+    fn show(&self, image: RgbaImage, label: Option<&str>) {
+        let mut images = self
+            .shared
+            .images
+            .lock()
+            .expect("shown images should not be poisoned");
+        if images.len() == MAX_SHOWN_IMAGES {
+            images.pop_front();
+        }
+        images.push_back(ShownImage {
+            label: label.map(ToString::to_string),
+            image,
+        });
+    }
+
+    fn send_to_mixer(&self, command: MixerCommand) -> Result<(), String> {
+        let mixer = self
+            .shared
+            .mixer
+            .lock()
+            .expect("mixer connection should not be poisoned");
+        let Some(tx) = mixer.as_ref() else {
+            return Err("audio can't be played until the mixer has started".to_string());
+        };
+        tx.send(command)
+            .map_err(|_| "could not send audio to the mixer".to_string())
+    }
+
+    /// Finds which writable directory a path is in, the innermost if they're nested,
+    /// along with where the path really points.
+    fn writable_dir_of(&self, path: &Path) -> Result<(PathBuf, PathBuf), String> {
+        let denied = || {
+            format!(
+                "{} is outside the project and {RECORDINGS_DIR} directories, so main.ua \
+                can't change it",
+                path.display()
+            )
+        };
+        let resolved = resolve(path).map_err(|_| denied())?;
+        self.writable_dirs
+            .iter()
+            .filter_map(|d| resolve(d).ok())
+            .filter(|d| resolved.starts_with(d))
+            .max_by_key(|d| d.components().count())
+            .map(|d| (d, resolved))
+            .ok_or_else(denied)
+    }
+
+    fn check_writable(&self, path: &Path) -> Result<(), String> {
+        self.writable_dir_of(path).map(|_| ())
+    }
+
+    /// Deleting a path also deletes everything in it, so it's denied if it is or holds
+    /// a writable directory, or is, holds or is inside a protected path.
+    fn check_deletable(&self, path: &Path) -> Result<(), String> {
+        let (_, resolved) = self.writable_dir_of(path)?;
+        let holds = |d: &PathBuf| d.starts_with(&resolved);
+        let mut writable = self.writable_dirs.iter().filter_map(|d| resolve(d).ok());
+        let mut protected = self.protected_paths.iter().filter_map(|p| resolve(p).ok());
+        if writable.any(|d| holds(&d)) || protected.any(|p| holds(&p) || resolved.starts_with(p)) {
+            Err(format!("main.ua can't delete {}", path.display()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl SysBackend for LimitedBackend {
     fn any(&self) -> &dyn Any {
         self
@@ -26,23 +348,394 @@ impl SysBackend for LimitedBackend {
         NativeSys.read_lines(handle)
     }
 
+    // Printing straight to the terminal would draw over the TUI
+    fn print_str_stdout(&self, s: &str) -> Result<(), String> {
+        self.log(s, false);
+        Ok(())
+    }
+    fn print_str_stderr(&self, s: &str) -> Result<(), String> {
+        self.log(s, true);
+        Ok(())
+    }
+    fn print_str_trace(&self, s: &str) {
+        self.log(s, true);
+    }
+    // `&w` can write to stdout and stderr too
+    fn write(&self, handle: Handle, contents: &[u8]) -> Result<(), String> {
+        match handle {
+            Handle::STDOUT => self.log(&String::from_utf8_lossy(contents), false),
+            Handle::STDERR => self.log(&String::from_utf8_lossy(contents), true),
+            _ => return NativeSys.write(handle, contents),
+        }
+        Ok(())
+    }
+
+    fn show_image(&self, image: DynamicImage, label: Option<&str>) -> Result<(), String> {
+        self.show(image.to_rgba8(), label);
+        Ok(())
+    }
+    fn show_gif(&self, gif_bytes: Vec<u8>, label: Option<&str>) -> Result<(), String> {
+        let image = image::load_from_memory_with_format(&gif_bytes, ImageFormat::Gif)
+            .map_err(|e| format!("could not decode GIF: {e}"))?;
+        self.show(image.to_rgba8(), label);
+        Ok(())
+    }
+
+    // The terminal is in raw mode, so stdin has to be read by whoever is drawing it
+    fn scan_line_stdin(&self) -> Result<Option<String>, String> {
+        let unavailable = || "there's nowhere to ask for input right now".to_string();
+        let (tx, reply) = channel();
+        self.shared
+            .input
+            .lock()
+            .expect("input connection should not be poisoned")
+            .as_ref()
+            .ok_or_else(unavailable)?
+            .send(tx)
+            .map_err(|_| unavailable())?;
+        reply.recv().map_err(|_| unavailable())
+    }
+
+    fn audio_sample_rate(&self) -> u32 {
+        *SAMPLE_RATE
+    }
+    fn play_audio(&self, wave_bytes: Vec<u8>, _label: Option<&str>) -> Result<(), String> {
+        let decoder = Decoder::new(Cursor::new(wave_bytes))
+            .map_err(|e| format!("could not decode audio: {e}"))?;
+        let samples: Vec<f32> =
+            UniformSourceIterator::new(decoder, CHANNEL_COUNT, *SAMPLE_RATE).collect();
+        self.send_to_mixer(MixerCommand::Play(SamplesBuffer::new(
+            CHANNEL_COUNT,
+            *SAMPLE_RATE,
+            samples,
+        )))
+    }
+    fn stream_audio(&self, f: AudioStreamFn) -> Result<(), String> {
+        self.send_to_mixer(MixerCommand::Stream(Box::new(AudioStream::spawn(f))))
+    }
+
+    fn change_directory(&self, _path: &str) -> Result<(), String> {
+        Err("main.ua can't change the working directory".to_string())
+    }
+    fn create_file(&self, path: &Path) -> Result<Handle, String> {
+        self.check_writable(path)?;
+        NativeSys.create_file(path)
+    }
+    fn delete(&self, path: &str) -> Result<(), String> {
+        self.check_deletable(Path::new(path))?;
+        NativeSys.delete(path)
+    }
+    fn file_write_all(&self, path: &Path, contents: &[u8]) -> Result<(), String> {
+        self.check_writable(path)?;
+        NativeSys.file_write_all(path, contents)
+    }
+    fn load_git_module(&self, url: &str, target: GitTarget) -> Result<PathBuf, String> {
+        if !self.allow_git {
+            return Err(format!(
+                "loading git modules is turned off, so {url} wasn't loaded. \
+                run uauauiua with --allow-git to turn it on"
+            ));
+        }
+        NativeSys.load_git_module(url, target)
+    }
+    fn make_dir(&self, path: &Path) -> Result<(), String> {
+        self.check_writable(path)?;
+        NativeSys.make_dir(path)
+    }
+    fn open_file(&self, path: &Path, write: bool) -> Result<Handle, String> {
+        if write {
+            self.check_writable(path)?;
+        }
+        NativeSys.open_file(path, write)
+    }
+
     native_call_methods!(
-        change_directory(path: &str) -> Result<(), String>;
         clipboard() -> Result<String, String>;
-        create_file(path: &Path) -> Result<Handle, String>;
-        delete(path: &str) -> Result<(), String>;
         file_exists(path: &str) -> bool;
         file_read_all(path: &Path) -> Result<Vec<u8>, String>;
-        file_write_all(path: &Path, contents: &[u8]) -> Result<(), String>;
         is_file(path: &str) -> Result<bool, String>;
         list_dir(path: &str) -> Result<Vec<String>, String>;
-        load_git_module(url: &str, target: GitTarget) -> Result<PathBuf, String>;
-        make_dir(path: &Path) -> Result<(), String>;
-        open_file(path: &Path, write: bool) -> Result<Handle, String>;
         read(handle: Handle, count: usize) -> Result<Vec<u8>, String>;
         read_all(handle: Handle) -> Result<Vec<u8>, String>;
         read_until(handle: Handle, delim: &[u8]) -> Result<Vec<u8>, String>;
         set_clipboard(contents: &str) -> Result<(), String>;
-        write(handle: Handle, contents: &[u8]) -> Result<(), String>;
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    /// A scratch project directory with a sibling directory outside of it.
+    struct Sandbox {
+        root: PathBuf,
+        project: PathBuf,
+        outside: PathBuf,
+    }
+
+    impl Sandbox {
+        fn new(name: &str) -> Self {
+            let root = env::temp_dir().join(format!("uauauiua-sandbox-{}-{name}", process::id()));
+            let project = root.join("project");
+            let outside = root.join("outside");
+            fs::create_dir_all(project.join(RECORDINGS_DIR)).unwrap();
+            fs::create_dir_all(&outside).unwrap();
+            Sandbox {
+                root,
+                project,
+                outside,
+            }
+        }
+
+        fn backend(&self, allow_git: bool) -> LimitedBackend {
+            LimitedBackend::with_writable_dirs(
+                vec![self.project.clone(), self.project.join(RECORDINGS_DIR)],
+                vec![self.project.join(MAIN_PATH), self.project.join(GIT_DIR)],
+                allow_git,
+                SharedState::default(),
+            )
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn writes_inside_project_are_allowed() {
+        let sandbox = Sandbox::new("write-inside");
+        let backend = sandbox.backend(false);
+
+        let file = sandbox.project.join("out.txt");
+        backend.file_write_all(&file, b"hi").unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"hi");
+
+        let recording = sandbox.project.join(RECORDINGS_DIR).join("new.txt");
+        backend.create_file(&recording).unwrap();
+        assert!(recording.exists());
+
+        let dir = sandbox.project.join("a").join("b");
+        backend.make_dir(&dir).unwrap();
+        assert!(dir.is_dir());
+
+        backend.open_file(&file, true).unwrap();
+    }
+
+    #[test]
+    fn writes_outside_project_are_denied() {
+        let sandbox = Sandbox::new("write-outside");
+        let backend = sandbox.backend(false);
+        let file = sandbox.outside.join("out.txt");
+
+        assert!(backend.file_write_all(&file, b"hi").is_err());
+        assert!(backend.create_file(&file).is_err());
+        assert!(backend.make_dir(&sandbox.outside.join("dir")).is_err());
+        assert!(!file.exists());
+
+        fs::write(&file, b"hi").unwrap();
+        assert!(backend.open_file(&file, true).is_err());
+        assert!(backend.open_file(&file, false).is_ok());
+        assert_eq!(backend.file_read_all(&file).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn parent_directories_cannot_escape_project() {
+        let sandbox = Sandbox::new("parent-dir");
+        let backend = sandbox.backend(false);
+        let escape = sandbox.project.join("..").join("outside").join("out.txt");
+
+        assert!(backend.file_write_all(&escape, b"hi").is_err());
+        assert!(!sandbox.outside.join("out.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_escape_project() {
+        let sandbox = Sandbox::new("symlink");
+        let backend = sandbox.backend(false);
+        let link = sandbox.project.join("link");
+        std::os::unix::fs::symlink(&sandbox.outside, &link).unwrap();
+
+        assert!(
+            backend
+                .file_write_all(&link.join("out.txt"), b"hi")
+                .is_err()
+        );
+        assert!(!sandbox.outside.join("out.txt").exists());
+    }
+
+    #[test]
+    fn deletes_are_confined_to_project() {
+        let sandbox = Sandbox::new("delete");
+        let backend = sandbox.backend(false);
+        let inside = sandbox.project.join("doomed.txt");
+        let outside = sandbox.outside.join("safe.txt");
+        fs::write(&inside, b"").unwrap();
+        fs::write(&outside, b"").unwrap();
+
+        backend.delete(inside.to_str().unwrap()).unwrap();
+        assert!(!inside.exists());
+
+        assert!(backend.delete(outside.to_str().unwrap()).is_err());
+        assert!(outside.exists());
+        assert!(backend.delete(sandbox.project.to_str().unwrap()).is_err());
+        assert!(sandbox.project.exists());
+    }
+
+    #[test]
+    fn project_files_cannot_be_deleted() {
+        let sandbox = Sandbox::new("delete-protected");
+        let backend = sandbox.backend(false);
+        let recordings = sandbox.project.join(RECORDINGS_DIR);
+        let main = sandbox.project.join(MAIN_PATH);
+        let git = sandbox.project.join(GIT_DIR);
+        let recording = recordings.join("take.wav");
+        fs::write(&main, b"").unwrap();
+        fs::create_dir(&git).unwrap();
+        fs::write(git.join("HEAD"), b"").unwrap();
+        fs::write(&recording, b"").unwrap();
+
+        for path in [
+            recordings.clone(),
+            recordings.join("."),
+            recordings.join("..").join(MAIN_PATH),
+            main.clone(),
+            git.clone(),
+            git.join("HEAD"),
+        ] {
+            assert!(
+                backend.delete(path.to_str().unwrap()).is_err(),
+                "{} should not be deletable",
+                path.display()
+            );
+        }
+        assert!(recording.exists() && main.exists() && git.join("HEAD").exists());
+
+        backend.delete(recording.to_str().unwrap()).unwrap();
+        assert!(!recording.exists());
+    }
+
+    #[test]
+    fn changing_directory_is_denied() {
+        let sandbox = Sandbox::new("cd");
+        let backend = sandbox.backend(false);
+        let before = env::current_dir().unwrap();
+
+        assert!(
+            backend
+                .change_directory(sandbox.outside.to_str().unwrap())
+                .is_err()
+        );
+        assert_eq!(env::current_dir().unwrap(), before);
+    }
+
+    #[test]
+    fn log_joins_partial_lines() {
+        let mut log = OutputLog::default();
+        log.push_str("a", false);
+        log.push_str("b\nc\n", false);
+        log.push_str("d", false);
+        log.push_str("e\n", true);
+
+        let lines: Vec<_> = log
+            .lines()
+            .iter()
+            .map(|l| (l.text.as_str(), l.is_stderr))
+            .collect();
+        assert_eq!(
+            lines,
+            [("ab", false), ("c", false), ("d", false), ("e", true)]
+        );
+    }
+
+    #[test]
+    fn log_drops_oldest_lines() {
+        let mut log = OutputLog::default();
+        for i in 0..=MAX_LOG_LINES {
+            log.push_str(&format!("{i}\n"), false);
+        }
+        assert_eq!(log.lines().len(), MAX_LOG_LINES);
+        assert_eq!(log.lines()[0].text, "1");
+    }
+
+    #[test]
+    fn standard_handles_are_logged() {
+        let sandbox = Sandbox::new("handles");
+        let backend = sandbox.backend(false);
+        backend.write(Handle::STDOUT, b"out\n").unwrap();
+        backend.write(Handle::STDERR, b"err\n").unwrap();
+
+        let log = backend.shared.log.lock().unwrap();
+        let lines: Vec<_> = log
+            .lines()
+            .iter()
+            .map(|l| (l.text.as_str(), l.is_stderr))
+            .collect();
+        assert_eq!(lines, [("out", false), ("err", true)]);
+    }
+
+    #[test]
+    fn input_is_asked_for_through_the_connection() {
+        let sandbox = Sandbox::new("input");
+        let backend = sandbox.backend(false);
+        assert!(backend.scan_line_stdin().is_err());
+
+        let (tx, requests) = channel::<LineRequest>();
+        *backend.shared.input.lock().unwrap() = Some(tx);
+        let answerer = std::thread::spawn(move || {
+            requests
+                .recv()
+                .unwrap()
+                .send(Some("hi".to_string()))
+                .unwrap();
+        });
+        assert_eq!(backend.scan_line_stdin().unwrap().as_deref(), Some("hi"));
+        answerer.join().unwrap();
+        assert!(backend.scan_line_stdin().is_err());
+    }
+
+    #[test]
+    fn streams_play_silence_until_their_blocks_are_ready() {
+        let (go, gate) = channel::<()>();
+        let mut stream = AudioStream::spawn(Box::new(move |times| {
+            // Each block waits for the go-ahead, and the stream ends once there isn't one
+            Ok(match gate.recv() {
+                Ok(()) => times.iter().map(|&t| [1.0 + t, -1.0 - t]).collect(),
+                Err(_) => Vec::new(),
+            })
+        }));
+
+        assert_eq!(stream.next(), Some(0.0));
+        assert_eq!(stream.next(), Some(0.0));
+
+        go.send(()).unwrap();
+        let first = std::iter::from_fn(|| stream.next())
+            .find(|&x| x != 0.0)
+            .unwrap();
+        assert_eq!(first, 1.0);
+        assert_eq!(stream.next(), Some(-1.0));
+
+        drop(go);
+        let rest = std::iter::from_fn(|| stream.next())
+            .filter(|&x| x != 0.0)
+            .count();
+        assert_eq!(
+            rest,
+            usize::try_from(STREAM_BLOCK_FRAMES).unwrap() * usize::from(CHANNEL_COUNT) - 2
+        );
+    }
+
+    #[test]
+    fn git_modules_need_permission() {
+        let sandbox = Sandbox::new("git");
+        let err = sandbox
+            .backend(false)
+            .load_git_module("https://example.com/module", GitTarget::Default)
+            .unwrap_err();
+        assert!(err.contains("--allow-git"));
+    }
+}
@@ -1,13 +1,122 @@
+mod control;
+mod disk_recording;
+mod export;
+mod flac;
+mod keyboard;
 mod limited_backend;
+mod midi;
+mod osc;
+mod output;
+mod palette;
+mod performance;
+mod preview;
 mod recording;
+mod render;
+mod script;
+mod spectrum;
 mod tui;
 mod uauauiua;
 mod uiua_extension;
 
+use std::{io, path::PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(feature = "midi")]
+use midi::VirtualInput;
+use osc::{DEFAULT_OSC_ADDRESS, OscServer};
+use output::OutputConfig;
+use script::ScriptSession;
 use tui::Tui;
+use uauauiua::Uauauiua;
+
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Where to play audio. Falls back to null if the device or file can't be opened
+    #[arg(long, value_enum, default_value_t = OutputKind::Device)]
+    output: OutputKind,
+    /// File written by the file output
+    #[arg(long, default_value = "output.wav")]
+    output_file: PathBuf,
+    /// Open a virtual MIDI input port named uauauiua that plays notes through OnNote
+    #[cfg(feature = "midi")]
+    #[arg(long)]
+    midi: bool,
+    /// Listen for OSC messages like /key/z over UDP
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        num_args = 0..=1,
+        default_missing_value = DEFAULT_OSC_ADDRESS
+    )]
+    osc: Option<String>,
+    /// Let main.ua import modules from git repositories, which downloads and runs their
+    /// code
+    #[arg(long)]
+    allow_git: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputKind {
+    Device,
+    Null,
+    File,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render a performance log or MIDI file with the sounds in main.ua to a WAV file, without
+    /// playing it
+    Render {
+        /// Performance log to play, like the .events files saved with file recordings,
+        /// or a .mid file
+        events: PathBuf,
+        /// WAV file to write
+        output: PathBuf,
+    },
+    /// Read commands like `press z` or `record main start` a line at a time and reply to
+    /// each with a line of JSON
+    Control {
+        /// Take commands from connections to a Unix socket at this path instead of stdin
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+}
 
-fn main() {
-    let terminal = ratatui::init();
-    Tui::default().run(terminal);
-    ratatui::restore();
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let output_config = match args.output {
+        OutputKind::Device => OutputConfig::Device,
+        OutputKind::Null => OutputConfig::Null,
+        OutputKind::File => OutputConfig::File(args.output_file),
+    };
+    match args.command {
+        Some(Command::Render { events, output }) => {
+            render::render(&events, &output, args.allow_git)
+        }
+        Some(Command::Control { socket }) => {
+            let mut uauauiua = Uauauiua::new(output_config, args.allow_git);
+            uauauiua.recover_recordings()?;
+            uauauiua.load()?;
+            let mut session = ScriptSession::new(uauauiua);
+            match socket {
+                Some(path) => session.serve_socket(&path),
+                None => Ok(session.serve(io::stdin().lock(), io::stdout().lock())?),
+            }
+        }
+        None => {
+            #[cfg(feature = "midi")]
+            let midi_input = args.midi.then(VirtualInput::open).transpose()?;
+            let osc_server = args.osc.map(OscServer::bind).transpose()?;
+            let terminal = ratatui::init();
+            let tui = Tui::new(output_config, args.allow_git, osc_server);
+            #[cfg(feature = "midi")]
+            let tui = tui.with_midi_input(midi_input);
+            tui.run(terminal);
+            ratatui::restore();
+            Ok(())
+        }
+    }
 }
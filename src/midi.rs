@@ -0,0 +1,390 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail};
+use crossterm::event::KeyCode;
+use indexmap::IndexMap;
+#[cfg(feature = "midi")]
+use midly::live::LiveEvent;
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use rodio::buffer::SamplesBuffer;
+
+use crate::recording::{ScheduledAction, ScheduledEvent};
+
+// 120 BPM, which MIDI assumes until a file says otherwise
+const DEFAULT_MICROSECONDS_PER_BEAT: u32 = 500_000;
+const MAX_VELOCITY: u8 = 127;
+#[cfg(all(unix, feature = "midi"))]
+const VIRTUAL_PORT_NAME: &str = "uauauiua";
+
+/// A note starting, in frames since the start of the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Note {
+    pub frame: u64,
+    pub key: u8,
+    pub velocity: u8,
+}
+
+pub fn is_midi_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("mid") || e.eq_ignore_ascii_case("midi"))
+}
+
+/// Reads the notes from every track of a MIDI file, in playback order. Note lengths are
+/// ignored since sounds always play to the end.
+pub fn read_notes(path: &Path, sample_rate: u32) -> anyhow::Result<Vec<Note>> {
+    let bytes = fs::read(path).map_err(|e| anyhow!("could not read {}: {e}", path.display()))?;
+    let smf = Smf::parse(&bytes).map_err(|e| anyhow!("could not parse {}: {e}", path.display()))?;
+    smf_notes(&smf, sample_rate)
+}
+
+fn smf_notes(smf: &Smf, sample_rate: u32) -> anyhow::Result<Vec<Note>> {
+    if smf.header.format == Format::Sequential {
+        bail!("sequential MIDI files are not supported");
+    }
+
+    // Tempo changes can be in any track, so everything has to be merged before ticks
+    // can be converted to time
+    let mut events: Vec<(u64, TrackEventKind)> = smf
+        .tracks
+        .iter()
+        .flat_map(|track| {
+            track.iter().scan(0, |tick: &mut u64, e| {
+                *tick += u64::from(e.delta.as_int());
+                Some((*tick, e.kind))
+            })
+        })
+        .collect();
+    events.sort_by_key(|&(tick, _)| tick);
+
+    let seconds_per_tick = |microseconds_per_beat: u32| match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => {
+            f64::from(microseconds_per_beat) / 1e6 / f64::from(ticks_per_beat.as_int().max(1))
+        }
+        Timing::Timecode(fps, subframes) => {
+            1.0 / (f64::from(fps.as_f32()) * f64::from(subframes.max(1)))
+        }
+    };
+
+    let mut notes = Vec::new();
+    let mut tick_length = seconds_per_tick(DEFAULT_MICROSECONDS_PER_BEAT);
+    let mut last_tick = 0;
+    let mut seconds = 0.0;
+    for (tick, kind) in events {
+        #[allow(clippy::cast_precision_loss)]
+        let elapsed = (tick - last_tick) as f64;
+        seconds += elapsed * tick_length;
+        last_tick = tick;
+
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
+                tick_length = seconds_per_tick(t.as_int());
+            }
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } if vel.as_int() > 0 => {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let frame = (seconds * f64::from(sample_rate)).round() as u64;
+                notes.push(Note {
+                    frame,
+                    key: key.as_int(),
+                    velocity: vel.as_int(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(notes)
+}
+
+/// How much to scale a sound by for how hard its note was played.
+pub fn velocity_gain(velocity: u8) -> f32 {
+    f32::from(velocity) / f32::from(MAX_VELOCITY)
+}
+
+/// Turns notes into a schedule for the mixer using the keys `note_keys` assigns them,
+/// scaling each sound by its note's velocity. Notes without a key or a sound are
+/// skipped.
+pub fn schedule_notes(
+    notes: Vec<Note>,
+    note_keys: &IndexMap<u8, KeyCode>,
+    mut key_source: impl FnMut(KeyCode) -> Option<SamplesBuffer<f32>>,
+) -> Vec<ScheduledEvent> {
+    notes
+        .into_iter()
+        .filter_map(|n| {
+            let key = *note_keys.get(&n.key)?;
+            Some(ScheduledEvent {
+                frame: n.frame,
+                action: ScheduledAction::Press(key, key_source(key)?, velocity_gain(n.velocity)),
+            })
+        })
+        .collect()
+}
+
+/// A note played on a MIDI controller.
+#[cfg(feature = "midi")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoteOn {
+    pub key: u8,
+    pub velocity: u8,
+}
+
+/// Picks the notes out of what a controller sends. Note offs are ignored since sounds
+/// always play to the end.
+#[cfg(feature = "midi")]
+fn parse_note_on(message: &[u8]) -> Option<NoteOn> {
+    match LiveEvent::parse(message) {
+        Ok(LiveEvent::Midi {
+            message: MidiMessage::NoteOn { key, vel },
+            ..
+        }) if vel.as_int() > 0 => Some(NoteOn {
+            key: key.as_int(),
+            velocity: vel.as_int(),
+        }),
+        _ => None,
+    }
+}
+
+/// A MIDI input port named uauauiua that controllers and programs like `aplaymidi` can
+/// be connected to.
+#[cfg(feature = "midi")]
+pub struct VirtualInput {
+    _connection: midir::MidiInputConnection<()>,
+    notes: std::sync::mpsc::Receiver<NoteOn>,
+}
+
+#[cfg(feature = "midi")]
+impl VirtualInput {
+    #[cfg(unix)]
+    pub fn open() -> anyhow::Result<Self> {
+        use std::sync::mpsc::channel;
+
+        use midir::{Ignore, MidiInput, os::unix::VirtualInput as _};
+
+        let mut input = MidiInput::new(VIRTUAL_PORT_NAME)
+            .map_err(|e| anyhow!("could not start MIDI input: {e}"))?;
+        input.ignore(Ignore::All);
+
+        let (tx, notes) = channel();
+        let connection = input
+            .create_virtual(
+                VIRTUAL_PORT_NAME,
+                move |_, message, _| {
+                    if let Some(note) = parse_note_on(message) {
+                        let _ = tx.send(note);
+                    }
+                },
+                (),
+            )
+            .map_err(|e| anyhow!("could not create virtual MIDI port: {e}"))?;
+
+        Ok(VirtualInput {
+            _connection: connection,
+            notes,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open() -> anyhow::Result<Self> {
+        bail!("virtual MIDI ports are only available on Linux and macOS")
+    }
+
+    /// Returns the notes played since the last call.
+    pub fn notes(&self) -> impl Iterator<Item = NoteOn> + '_ {
+        self.notes.try_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Header, TrackEvent, num::u28};
+
+    use super::*;
+
+    // Makes the arithmetic easy
+    const SAMPLE_RATE: u32 = 1000;
+    const TICKS_PER_BEAT: u16 = 96;
+
+    fn tempo(microseconds_per_beat: u32) -> TrackEventKind<'static> {
+        TrackEventKind::Meta(MetaMessage::Tempo(microseconds_per_beat.into()))
+    }
+
+    fn note_on(key: u8, velocity: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOn {
+                key: key.into(),
+                vel: velocity.into(),
+            },
+        }
+    }
+
+    /// Writes a file with tracks of events and the ticks between them, then parses it
+    /// back like one read from disk.
+    fn notes_in(
+        format: Format,
+        tracks: &[&[(u32, TrackEventKind<'static>)]],
+    ) -> anyhow::Result<Vec<Note>> {
+        let mut smf = Smf::new(Header::new(format, Timing::Metrical(TICKS_PER_BEAT.into())));
+        for track in tracks {
+            let mut events: Vec<TrackEvent> = track
+                .iter()
+                .map(|&(delta, kind)| TrackEvent {
+                    delta: u28::new(delta),
+                    kind,
+                })
+                .collect();
+            events.push(TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            });
+            smf.tracks.push(events);
+        }
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        smf_notes(&Smf::parse(&bytes).unwrap(), SAMPLE_RATE)
+    }
+
+    fn frames(notes: &[Note]) -> Vec<u64> {
+        notes.iter().map(|n| n.frame).collect()
+    }
+
+    #[test]
+    fn follows_tempo_changes() {
+        let notes = notes_in(
+            Format::SingleTrack,
+            &[&[
+                // Half a second per beat until the tempo halves
+                (0, note_on(60, 100)),
+                (96, tempo(1_000_000)),
+                (0, note_on(62, 100)),
+                (96, note_on(64, 50)),
+            ]],
+        )
+        .unwrap();
+        assert_eq!(frames(&notes), [0, 500, 1500]);
+        assert_eq!(
+            notes[2],
+            Note {
+                frame: 1500,
+                key: 64,
+                velocity: 50
+            }
+        );
+    }
+
+    #[test]
+    fn merges_parallel_tracks() {
+        let notes = notes_in(
+            Format::Parallel,
+            &[
+                // The tempo track applies to the others
+                &[(0, tempo(250_000))],
+                &[(96, note_on(60, 100))],
+                &[(48, note_on(62, 100)), (96, note_on(64, 0))],
+            ],
+        )
+        .unwrap();
+        // Note ons with no velocity are note offs
+        assert_eq!(frames(&notes), [125, 250]);
+        assert_eq!(notes[0].key, 62);
+    }
+
+    #[test]
+    fn rejects_sequential_files() {
+        let result = notes_in(Format::Sequential, &[&[(0, note_on(60, 100))]]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn schedules_notes_with_keys_and_sounds() {
+        let notes = [(0, 60, 127), (10, 61, 127), (20, 62, 127), (30, 60, 0)].map(
+            |(frame, key, velocity)| Note {
+                frame,
+                key,
+                velocity,
+            },
+        );
+        let note_keys = IndexMap::from([(60, KeyCode::Char('a')), (61, KeyCode::Char('b'))]);
+        let schedule = schedule_notes(notes.to_vec(), &note_keys, |k| {
+            (k == KeyCode::Char('a')).then(|| SamplesBuffer::new(2, SAMPLE_RATE, vec![0.5, 0.5]))
+        });
+
+        let frames: Vec<u64> = schedule.iter().map(|e| e.frame).collect();
+        assert_eq!(frames, [0, 30]);
+        let ScheduledAction::Press(key, _, gain) = schedule[0].action else {
+            panic!("expected a press");
+        };
+        assert_eq!((key, gain), (KeyCode::Char('a'), 1.0));
+        let ScheduledAction::Press(_, _, gain) = schedule[1].action else {
+            panic!("expected a press");
+        };
+        assert_eq!(gain, 0.0);
+    }
+
+    #[cfg(feature = "midi")]
+    #[test]
+    fn picks_out_note_ons() {
+        assert_eq!(
+            parse_note_on(&[0x93, 60, 100]),
+            Some(NoteOn {
+                key: 60,
+                velocity: 100
+            })
+        );
+        // Note offs, including note ons without velocity, and everything else
+        assert_eq!(parse_note_on(&[0x93, 60, 0]), None);
+        assert_eq!(parse_note_on(&[0x83, 60, 100]), None);
+        assert_eq!(parse_note_on(&[0xB0, 7, 100]), None);
+        assert_eq!(parse_note_on(&[0x90, 60]), None);
+    }
+
+    #[cfg(all(unix, feature = "midi"))]
+    #[test]
+    #[ignore = "needs a MIDI sequencer, which build machines often don't have"]
+    fn receives_notes_from_a_software_sender() {
+        use std::{
+            thread,
+            time::{Duration, Instant},
+        };
+
+        use midir::MidiOutput;
+
+        let input = VirtualInput::open().unwrap();
+        let output = MidiOutput::new("uauauiua test").unwrap();
+        let port = output
+            .ports()
+            .into_iter()
+            .find(|p| {
+                output
+                    .port_name(p)
+                    .is_ok_and(|n| n.contains(VIRTUAL_PORT_NAME))
+            })
+            .expect("virtual port should be visible to senders");
+        let mut connection = output.connect(&port, "uauauiua test").unwrap();
+        for message in [[0x90, 60, 100], [0x80, 60, 0], [0x91, 62, 1]] {
+            connection.send(&message).unwrap();
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut notes = Vec::new();
+        while notes.len() < 2 && Instant::now() < deadline {
+            notes.extend(input.notes());
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            notes,
+            [
+                NoteOn {
+                    key: 60,
+                    velocity: 100
+                },
+                NoteOn {
+                    key: 62,
+                    velocity: 1
+                }
+            ]
+        );
+    }
+}
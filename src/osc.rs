@@ -0,0 +1,189 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    str,
+    sync::mpsc::{Receiver, channel},
+    thread,
+};
+
+use anyhow::{anyhow, ensure};
+
+use crate::control::ControlCommand;
+
+pub const DEFAULT_OSC_ADDRESS: &str = "127.0.0.1:9000";
+const MAX_PACKET_LEN: usize = 65_536;
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+const TIME_TAG_LEN: usize = 8;
+
+/// Reads a null-terminated string padded to four bytes, returning it and what's after it.
+fn read_string(bytes: &[u8]) -> anyhow::Result<(&str, &[u8])> {
+    let end = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(anyhow!("unterminated OSC string"))?;
+    let s = str::from_utf8(&bytes[..end])?;
+    let padded_len = (end + 1).next_multiple_of(4);
+    Ok((s, bytes.get(padded_len..).unwrap_or_default()))
+}
+
+/// Turns each message in an OSC packet into a command. Arguments are ignored, and
+/// bundles run as soon as they arrive whatever their time tag says.
+fn decode_packet(packet: &[u8], commands: &mut Vec<anyhow::Result<ControlCommand>>) {
+    let Some(bundle) = packet.strip_prefix(BUNDLE_TAG) else {
+        commands.push(read_string(packet).and_then(|(address, _)| {
+            ensure!(
+                address.starts_with('/'),
+                "'{address}' is not an OSC address"
+            );
+            address.parse()
+        }));
+        return;
+    };
+
+    let mut rest = bundle.get(TIME_TAG_LEN..).unwrap_or_default();
+    while let Some((len, tail)) = rest.split_first_chunk::<4>() {
+        let len = u32::from_be_bytes(*len) as usize;
+        let Some(element) = tail.get(..len) else {
+            commands.push(Err(anyhow!("truncated OSC bundle")));
+            return;
+        };
+        decode_packet(element, commands);
+        rest = &tail[len..];
+    }
+}
+
+/// Listens for OSC messages over UDP on a thread of its own.
+pub struct OscServer {
+    local_addr: SocketAddr,
+    commands: Receiver<anyhow::Result<ControlCommand>>,
+}
+
+impl OscServer {
+    pub fn bind(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        let local_addr = socket.local_addr()?;
+        let (tx, commands) = channel();
+
+        thread::spawn(move || {
+            let mut buf = vec![0; MAX_PACKET_LEN];
+            while let Ok(len) = socket.recv(&mut buf) {
+                let mut decoded = Vec::new();
+                decode_packet(&buf[..len], &mut decoded);
+                if decoded.into_iter().try_for_each(|c| tx.send(c)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(OscServer {
+            local_addr,
+            commands,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the commands received since the last call, or why a message couldn't be
+    /// understood.
+    pub fn commands(&self) -> impl Iterator<Item = anyhow::Result<ControlCommand>> + '_ {
+        self.commands.try_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crossterm::event::KeyCode;
+
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+        bytes
+    }
+
+    fn message(address: &str) -> Vec<u8> {
+        let mut bytes = string(address);
+        bytes.extend(string(",f"));
+        bytes.extend(1.0_f32.to_be_bytes());
+        bytes
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = BUNDLE_TAG.to_vec();
+        bytes.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        for e in elements {
+            bytes.extend(u32::try_from(e.len()).unwrap().to_be_bytes());
+            bytes.extend(e);
+        }
+        bytes
+    }
+
+    fn decode(packet: &[u8]) -> Vec<anyhow::Result<ControlCommand>> {
+        let mut commands = Vec::new();
+        decode_packet(packet, &mut commands);
+        commands
+    }
+
+    #[test]
+    fn decodes_messages_and_bundles() {
+        let packet = bundle(&[
+            message("/key/z"),
+            bundle(&[message("/hold/x"), message("/stop")]),
+            message("/record/main/start"),
+        ]);
+        let commands: Vec<_> = decode(&packet).into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            commands,
+            [
+                ControlCommand::Press(KeyCode::Char('z')),
+                ControlCommand::ToggleHold(KeyCode::Char('x')),
+                ControlCommand::StopPlayback,
+                ControlCommand::StartMainRecording,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_messages() {
+        assert!(decode(&message("/key/zz"))[0].is_err());
+        assert!(decode(&message("/explode"))[0].is_err());
+        assert!(decode(b"/stop").first().unwrap().is_err());
+
+        let mut truncated = bundle(&[message("/stop")]);
+        truncated.pop();
+        assert!(decode(&truncated)[0].is_err());
+    }
+
+    #[test]
+    fn receives_commands_from_a_client() {
+        let server = OscServer::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(&message("/reload"), server.local_addr())
+            .unwrap();
+        client
+            .send_to(&message("/record/uiua/stop"), server.local_addr())
+            .unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let command = server
+                .commands
+                .recv_timeout(Duration::from_secs(5))
+                .expect("server should have received the message");
+            received.push(command.unwrap());
+        }
+        assert_eq!(
+            received,
+            [
+                ControlCommand::Reload,
+                ControlCommand::StopSecondaryRecording
+            ]
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::ensure;
+use hound::WavWriter;
+use rodio::{OutputStream, OutputStreamHandle, Sink};
+
+use crate::disk_recording::float_wav_spec;
+use crate::recording::{CHANNEL_COUNT, Mixer, SAMPLE_RATE, duration_to_samples};
+
+const PULL_PERIOD: Duration = Duration::from_millis(10);
+
+/// Where the mixer's output goes.
+#[derive(Clone)]
+pub enum OutputConfig {
+    /// The default sound device
+    Device,
+    /// Nowhere, but still played in real time so recordings work as usual
+    Null,
+    /// A 32-bit float WAV file, played in real time
+    File(PathBuf),
+}
+
+/// A thread that pulls samples from the mixer at the rate a sound device would.
+pub struct PullThread {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PullThread {
+    fn spawn(mut mixer: Mixer, mut sink: impl FnMut(f32) + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let samples_per_period = duration_to_samples(PULL_PERIOD).map_or(1, |s| s.max(1));
+                let start = Instant::now();
+                let mut samples_pulled: u64 = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    mixer.by_ref().take(samples_per_period).for_each(&mut sink);
+                    samples_pulled += samples_per_period as u64;
+
+                    let frames_pulled = samples_pulled / u64::from(CHANNEL_COUNT);
+                    let due = start + Duration::from_secs(frames_pulled) / *SAMPLE_RATE;
+                    if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                        thread::sleep(wait);
+                    }
+                }
+            }
+        });
+        PullThread {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for PullThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+pub enum Output {
+    Device {
+        _stream: OutputStream,
+        _stream_handle: OutputStreamHandle,
+        _sink: Sink,
+    },
+    Pulled {
+        _thread: PullThread,
+    },
+}
+
+fn open_device() -> anyhow::Result<(OutputStream, OutputStreamHandle, Sink)> {
+    let (stream, stream_handle) = OutputStream::try_default()?;
+    let sink = Sink::try_new(&stream_handle)?;
+    Ok((stream, stream_handle, sink))
+}
+
+fn open_file(path: &Path, append: bool) -> anyhow::Result<WavWriter<BufWriter<File>>> {
+    if append && path.exists() {
+        let writer = WavWriter::append(path)?;
+        ensure!(
+            writer.spec() == float_wav_spec(),
+            "{} is not in the mixer's output format",
+            path.display()
+        );
+        Ok(writer)
+    } else {
+        Ok(WavWriter::create(path, float_wav_spec())?)
+    }
+}
+
+impl Output {
+    /// Starts playing the mixer as configured, falling back to discarding its output if
+    /// that isn't possible. Returns a warning describing the fallback, if there was one.
+    ///
+    /// `append` continues an existing output file rather than starting it over, for
+    /// when audio is reinitialized.
+    pub fn open(config: &OutputConfig, append: bool, mixer: Mixer) -> (Self, Option<String>) {
+        let warning = match config {
+            OutputConfig::Device => match open_device() {
+                Ok((stream, stream_handle, sink)) => {
+                    sink.append(mixer);
+                    let output = Output::Device {
+                        _stream: stream,
+                        _stream_handle: stream_handle,
+                        _sink: sink,
+                    };
+                    return (output, None);
+                }
+                Err(e) => format!("could not open audio device ({e}), so nothing will be heard"),
+            },
+            OutputConfig::Null => {
+                return (
+                    Output::Pulled {
+                        _thread: PullThread::spawn(mixer, |_| {}),
+                    },
+                    None,
+                );
+            }
+            OutputConfig::File(path) => match open_file(path, append) {
+                Ok(mut writer) => {
+                    // A failed write stops the file from growing, but playback and
+                    // recordings carry on. The file is finalized when the thread drops
+                    // the writer
+                    let mut failed = false;
+                    let thread = PullThread::spawn(mixer, move |sample| {
+                        failed = failed || writer.write_sample(sample).is_err();
+                    });
+                    return (Output::Pulled { _thread: thread }, None);
+                }
+                Err(e) => format!(
+                    "could not write audio to {} ({e}), so nothing will be heard",
+                    path.display()
+                ),
+            },
+        };
+        (
+            Output::Pulled {
+                _thread: PullThread::spawn(mixer, |_| {}),
+            },
+            Some(warning),
+        )
+    }
+}
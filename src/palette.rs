@@ -0,0 +1,69 @@
+/// How well `query` matches `text`, or `None` if it doesn't. Each word of the query has to
+/// appear in the text with its letters in order, though not necessarily next to each
+/// other, ignoring case. Letters that follow each other or start words count for more.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<u32> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    query
+        .to_lowercase()
+        .split_whitespace()
+        .map(|word| word_score(word, &text))
+        .sum()
+}
+
+fn word_score(word: &str, text: &[char]) -> Option<u32> {
+    let mut score = 0;
+    let mut next = 0;
+    let mut previous_match = None;
+    for c in word.chars() {
+        let i = next + text[next..].iter().position(|&t| t == c)?;
+        score += 1;
+        if previous_match.is_some_and(|p| p + 1 == i) {
+            score += 2;
+        }
+        if i == 0 || !text[i - 1].is_alphanumeric() {
+            score += 3;
+        }
+        previous_match = Some(i);
+        next = i + 1;
+    }
+    Some(score)
+}
+
+/// The items whose text matches `query`, best first. Items that match equally well keep
+/// their order, so an empty query gives back every item.
+pub fn fuzzy_filter<T: Copy>(items: &[T], query: &str, text: impl Fn(T) -> String) -> Vec<T> {
+    let mut matches: Vec<(u32, T)> = items
+        .iter()
+        .filter_map(|&item| Some((fuzzy_score(query, &text(item))?, item)))
+        .collect();
+    matches.sort_by(|(a, _), (b, _)| b.cmp(a));
+    matches.into_iter().map(|(_, item)| item).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_words_anywhere_in_order() {
+        assert!(fuzzy_score("reinit audio", "Reinitialize audio").is_some());
+        assert!(fuzzy_score("audio reinit", "Reinitialize audio").is_some());
+        assert!(fuzzy_score("clr rec", "Clear recordings").is_some());
+        assert!(fuzzy_score("recz", "Clear recordings").is_none());
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+    }
+
+    #[test]
+    fn ranks_closer_matches_first() {
+        let items = [
+            "Clear the stack",
+            "Start or stop file recording",
+            "Clear recordings",
+        ];
+        assert_eq!(
+            fuzzy_filter(&items, "clear rec", ToString::to_string),
+            ["Clear recordings", "Clear the stack"]
+        );
+        assert_eq!(fuzzy_filter(&items, "", ToString::to_string), items);
+    }
+}
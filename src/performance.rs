@@ -0,0 +1,184 @@
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail};
+use crossterm::event::KeyCode;
+use rodio::buffer::SamplesBuffer;
+
+use crate::recording::{HeldSource, ScheduledAction, ScheduledEvent};
+
+pub const EVENTS_EXTENSION: &str = "events";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PerformanceAction {
+    /// Along with the gain the key was played at, which MIDI velocity sets
+    Press(KeyCode, f32),
+    Hold(KeyCode),
+    Release(KeyCode),
+    Stop,
+}
+
+/// Something the mixer did, timestamped in frames since the recording started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PerformanceEvent {
+    pub frame: u64,
+    pub action: PerformanceAction,
+}
+
+impl fmt::Display for PerformanceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.frame)?;
+        match self.action {
+            PerformanceAction::Press(k, gain) => write!(f, "press {k} {gain}"),
+            PerformanceAction::Hold(k) => write!(f, "hold {k}"),
+            PerformanceAction::Release(k) => write!(f, "release {k}"),
+            PerformanceAction::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+pub fn parse_key(s: &str) -> anyhow::Result<KeyCode> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(KeyCode::Char(c)),
+        _ if s == "Space" => Ok(KeyCode::Char(' ')),
+        _ => bail!("expected a single character key, got '{s}'"),
+    }
+}
+
+impl FromStr for PerformanceEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (frame, action) = match words.as_slice() {
+            // Logs from before velocity was recorded leave the gain out
+            [frame, "press", key] => (frame, PerformanceAction::Press(parse_key(key)?, 1.0)),
+            [frame, "press", key, gain] => {
+                let gain = gain
+                    .parse()
+                    .map_err(|_| anyhow!("invalid gain '{gain}' in event '{s}'"))?;
+                (frame, PerformanceAction::Press(parse_key(key)?, gain))
+            }
+            [frame, "hold", key] => (frame, PerformanceAction::Hold(parse_key(key)?)),
+            [frame, "release", key] => (frame, PerformanceAction::Release(parse_key(key)?)),
+            [frame, "stop"] => (frame, PerformanceAction::Stop),
+            _ => bail!("could not parse event '{s}'"),
+        };
+        let frame = frame
+            .parse()
+            .map_err(|_| anyhow!("invalid frame '{frame}' in event '{s}'"))?;
+        Ok(PerformanceEvent { frame, action })
+    }
+}
+
+/// Reads an event log, converting its timestamps to the given sample rate and sorting
+/// them into playback order.
+pub fn read_events(path: &Path, sample_rate: u32) -> anyhow::Result<Vec<PerformanceEvent>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| anyhow!("could not read {}: {e}", path.display()))?;
+
+    let mut log_sample_rate = sample_rate;
+    let mut events = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(rate) = line.strip_prefix("sample_rate ") {
+            log_sample_rate = rate
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&r| r > 0)
+                .ok_or_else(|| anyhow!("invalid sample rate on line {}", i + 1))?;
+            continue;
+        }
+        let event: PerformanceEvent = line.parse().map_err(|e| anyhow!("line {}: {e}", i + 1))?;
+        events.push(event);
+    }
+
+    if log_sample_rate != sample_rate {
+        for e in &mut events {
+            e.frame = e.frame * u64::from(sample_rate) / u64::from(log_sample_rate);
+        }
+    }
+    events.sort_by_key(|e| e.frame);
+    Ok(events)
+}
+
+/// Turns logged events into a schedule for the mixer, skipping any that press or hold
+/// a key `key_source` has no sound for.
+pub fn schedule_events(
+    events: Vec<PerformanceEvent>,
+    mut key_source: impl FnMut(KeyCode) -> Option<SamplesBuffer<f32>>,
+) -> Vec<ScheduledEvent> {
+    events
+        .into_iter()
+        .filter_map(|e| {
+            let action = match e.action {
+                PerformanceAction::Press(k, gain) => {
+                    ScheduledAction::Press(k, key_source(k)?, gain)
+                }
+                PerformanceAction::Hold(k) => {
+                    ScheduledAction::Hold(k, HeldSource::new(key_source(k)?))
+                }
+                PerformanceAction::Release(k) => ScheduledAction::Release(k),
+                PerformanceAction::Stop => ScheduledAction::Stop,
+            };
+            Some(ScheduledEvent {
+                frame: e.frame,
+                action,
+            })
+        })
+        .collect()
+}
+
+/// Writes the lines that start every event log.
+pub fn write_header(mut writer: impl Write, sample_rate: u32) -> io::Result<()> {
+    writeln!(writer, "# uauauiua performance log")?;
+    writeln!(
+        writer,
+        "# <frame> press <key> <gain>, <frame> hold|release <key>, or <frame> stop"
+    )?;
+    writeln!(writer, "sample_rate {sample_rate}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_round_trip_through_text() {
+        let events = [
+            PerformanceAction::Press(KeyCode::Char('a'), 0.503_937),
+            PerformanceAction::Press(KeyCode::Char(' '), 1.0),
+            PerformanceAction::Hold(KeyCode::Char('b')),
+            PerformanceAction::Release(KeyCode::Char('b')),
+            PerformanceAction::Stop,
+        ]
+        .map(|action| PerformanceEvent { frame: 42, action });
+        for event in events {
+            assert_eq!(
+                event.to_string().parse::<PerformanceEvent>().unwrap(),
+                event
+            );
+        }
+    }
+
+    #[test]
+    fn presses_without_a_gain_play_at_full_volume() {
+        assert_eq!(
+            "7 press a".parse::<PerformanceEvent>().unwrap(),
+            PerformanceEvent {
+                frame: 7,
+                action: PerformanceAction::Press(KeyCode::Char('a'), 1.0)
+            }
+        );
+        assert!("7 press a loud".parse::<PerformanceEvent>().is_err());
+    }
+}
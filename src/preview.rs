@@ -0,0 +1,276 @@
+use std::time::Duration;
+
+use image::{Rgba, RgbaImage};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::Color,
+    symbols::Marker,
+    widgets::{
+        Widget,
+        canvas::{Canvas, Line as CanvasLine},
+    },
+};
+use rodio::{Source, buffer::SamplesBuffer};
+use uiua::Value;
+
+use crate::spectrum::Spectrum;
+
+// Anything smaller is more likely a list of numbers than a picture, like an [n 2] sound
+const MIN_IMAGE_SIZE: usize = 8;
+// Draws two pixels per cell, the top one in the foreground and the bottom one in the
+// background
+const HALF_BLOCK: char = '▀';
+// Enough for a wide terminal, since each cell of a canvas is two Braille dots wide
+const WAVEFORM_POINTS: usize = 256;
+// The quietest level the spectrum shows
+const SPECTRUM_FLOOR_DB: f32 = -90.0;
+// Eighths of a cell, for the tops of the spectrum's bars
+const BAR_EIGHTHS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const PEAK_MARK: char = '▔';
+
+/// Turns an image-shaped array, [h w] for grayscale, [h w 3] for RGB or [h w 4] for RGBA
+/// with numbers from 0 to 1, into an image.
+pub fn value_to_image(value: &Value) -> Option<RgbaImage> {
+    let array = match value {
+        Value::Byte(x) => x.clone().convert::<f64>(),
+        Value::Num(x) => x.clone(),
+        _ => return None,
+    };
+    let (height, width, channels) = match *array.shape.dims() {
+        [h, w] => (h, w, 1),
+        [h, w, c @ (3 | 4)] => (h, w, c),
+        _ => return None,
+    };
+    if height < MIN_IMAGE_SIZE || width < MIN_IMAGE_SIZE {
+        return None;
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let to_byte = |x: f64| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+    let bytes: Vec<u8> = array
+        .elements()
+        .copied()
+        .collect::<Vec<f64>>()
+        .chunks_exact(channels)
+        .flat_map(|pixel| match *pixel {
+            [v] => [to_byte(v), to_byte(v), to_byte(v), u8::MAX],
+            [r, g, b] => [to_byte(r), to_byte(g), to_byte(b), u8::MAX],
+            [r, g, b, a] => [to_byte(r), to_byte(g), to_byte(b), to_byte(a)],
+            _ => unreachable!("pixels should have 1, 3 or 4 channels"),
+        })
+        .collect();
+    RgbaImage::from_raw(
+        u32::try_from(width).ok()?,
+        u32::try_from(height).ok()?,
+        bytes,
+    )
+}
+
+/// An image scaled down to fit its area, drawn with half blocks over a black background.
+pub struct ImagePreview<'a>(pub &'a RgbaImage);
+
+fn pixel_color(Rgba([r, g, b, a]): Rgba<u8>) -> Color {
+    #[allow(clippy::cast_possible_truncation)]
+    let blend = |x: u8| (u16::from(x) * u16::from(a) / u16::from(u8::MAX)) as u8;
+    Color::Rgb(blend(r), blend(g), blend(b))
+}
+
+impl Widget for ImagePreview<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let (width, height) = self.0.dimensions();
+        if width == 0 || height == 0 || area.is_empty() {
+            return;
+        }
+
+        // How many image pixels each drawn pixel covers, never scaling up
+        let scale = f64::max(
+            1.0,
+            f64::max(
+                f64::from(width) / f64::from(area.width),
+                f64::from(height) / (f64::from(area.height) * 2.0),
+            ),
+        );
+        // Drawn pixels past the bottom of the image are left black
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let pixel = |x: u16, y: u32| {
+            let x = ((f64::from(x) * scale) as u32).min(width - 1);
+            let y = (f64::from(y) * scale) as u32;
+            if y < height {
+                pixel_color(*self.0.get_pixel(x, y))
+            } else {
+                Color::Black
+            }
+        };
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let (columns, rows) = (
+            (f64::from(width) / scale).ceil() as u16,
+            (f64::from(height) / scale / 2.0).ceil() as u16,
+        );
+
+        for row in 0..rows.min(area.height) {
+            for column in 0..columns.min(area.width) {
+                let y = u32::from(row) * 2;
+                buf[(area.x + column, area.y + row)]
+                    .set_char(HALF_BLOCK)
+                    .set_fg(pixel(column, y))
+                    .set_bg(pixel(column, y + 1));
+            }
+        }
+    }
+}
+
+/// A sound boiled down to what's needed to draw it.
+pub struct Waveform {
+    pub duration: Duration,
+    pub peak: f32,
+    // The lowest and highest sample, across channels, in each of up to
+    // `WAVEFORM_POINTS` stretches of the sound
+    ranges: Vec<(f32, f32)>,
+}
+
+impl Waveform {
+    pub fn new(source: &SamplesBuffer<f32>) -> Self {
+        let channels = usize::from(source.channels().max(1));
+        let samples: Vec<f32> = source.clone().collect();
+        let frames = samples.len() / channels;
+        let stretch_len = frames.div_ceil(WAVEFORM_POINTS).max(1) * channels;
+        Waveform {
+            duration: source.total_duration().unwrap_or_default(),
+            peak: samples.iter().fold(0.0, |peak, x| f32::max(peak, x.abs())),
+            ranges: samples
+                .chunks(stretch_len)
+                .map(|c| {
+                    let min = c.iter().copied().fold(f32::INFINITY, f32::min);
+                    let max = c.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    (min, max)
+                })
+                .collect(),
+        }
+    }
+
+    /// The peak in decibels relative to full scale, or `None` for silence.
+    pub fn peak_db(&self) -> Option<f32> {
+        (self.peak > 0.0).then(|| 20.0 * self.peak.log10())
+    }
+}
+
+/// A waveform stretched across its area, with a vertical line for the range of each
+/// stretch of the sound.
+pub struct WaveformPreview<'a>(pub &'a Waveform);
+
+impl Widget for WaveformPreview<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let ranges = &self.0.ranges;
+        #[allow(clippy::cast_precision_loss)]
+        let len = ranges.len() as f64;
+        Canvas::default()
+            .marker(Marker::Braille)
+            .x_bounds([0.0, len.max(1.0)])
+            .y_bounds([-1.0, 1.0])
+            .paint(|ctx| {
+                for (i, &(min, max)) in ranges.iter().enumerate() {
+                    #[allow(clippy::cast_precision_loss)]
+                    let x = i as f64;
+                    ctx.draw(&CanvasLine {
+                        x1: x,
+                        y1: f64::from(min.clamp(-1.0, 1.0)),
+                        x2: x,
+                        y2: f64::from(max.clamp(-1.0, 1.0)),
+                        color: Color::Cyan,
+                    });
+                }
+            })
+            .render(area, buf);
+    }
+}
+
+/// A spectrum drawn as a bar per column, with the peak of each column marked above it.
+pub struct SpectrumView<'a> {
+    pub spectrum: &'a Spectrum,
+    pub log_axis: bool,
+    pub peak_hold: bool,
+}
+
+impl Widget for SpectrumView<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        if area.is_empty() {
+            return;
+        }
+        // How much of the area's height a level fills, in eighths of a cell
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let eighths = |db: f32| {
+            let ratio = (1.0 - db / SPECTRUM_FLOOR_DB).clamp(0.0, 1.0);
+            (ratio * f32::from(area.height) * 8.0).round() as u16
+        };
+
+        let bands = self.spectrum.bands(usize::from(area.width), self.log_axis);
+        for (x, (level, peak)) in (area.left()..area.right()).zip(bands) {
+            let filled = eighths(level);
+            for row in 0..area.height {
+                let y = area.bottom() - 1 - row;
+                let in_cell = filled.saturating_sub(row * 8).min(8);
+                buf[(x, y)]
+                    .set_char(BAR_EIGHTHS[usize::from(in_cell)])
+                    .set_fg(Color::Green);
+            }
+
+            let peak_row = eighths(peak).div_ceil(8);
+            if self.peak_hold && peak_row > filled.div_ceil(8) && peak_row <= area.height {
+                buf[(x, area.bottom() - peak_row)]
+                    .set_char(PEAK_MARK)
+                    .set_fg(Color::Yellow);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uiua::Array;
+
+    use super::*;
+
+    fn array<const N: usize>(shape: [usize; N], data: &[f64]) -> Value {
+        let mut array: Array<f64> = data.iter().copied().collect();
+        array.shape = shape.into();
+        array.into()
+    }
+
+    #[test]
+    fn converts_image_shaped_arrays() {
+        let gray = value_to_image(&array([8, 8], &[0.5; 64])).unwrap();
+        assert_eq!(gray.dimensions(), (8, 8));
+        assert_eq!(gray.get_pixel(3, 3), &Rgba([128, 128, 128, 255]));
+
+        let mut data = vec![0.0; 8 * 10 * 4];
+        data[..4].copy_from_slice(&[1.0, 0.0, 2.0, 0.5]);
+        let rgba = value_to_image(&array([8, 10, 4], &data)).unwrap();
+        assert_eq!(rgba.dimensions(), (10, 8));
+        assert_eq!(rgba.get_pixel(0, 0), &Rgba([255, 0, 255, 128]));
+    }
+
+    #[test]
+    fn summarizes_sounds() {
+        let samples: Vec<f32> = (0..1000)
+            .flat_map(|i| if i == 10 { [-0.5, 0.25] } else { [0.0, 0.125] })
+            .collect();
+        let waveform = Waveform::new(&SamplesBuffer::new(2, 1000, samples));
+        assert_eq!(waveform.duration, Duration::from_secs(1));
+        assert_eq!(waveform.peak, 0.5);
+        assert!((waveform.peak_db().unwrap() + 6.02).abs() < 0.01);
+        assert_eq!(waveform.ranges.len(), 250);
+        assert_eq!(waveform.ranges[2], (-0.5, 0.25));
+        assert_eq!(waveform.ranges[3], (0.0, 0.125));
+
+        let silence = Waveform::new(&SamplesBuffer::new(2, 1000, vec![0.0; 4]));
+        assert_eq!(silence.peak_db(), None);
+    }
+
+    #[test]
+    fn ignores_other_arrays() {
+        assert!(value_to_image(&array([100, 2], &[0.0; 200])).is_none());
+        assert!(value_to_image(&array([8, 8, 2], &[0.0; 128])).is_none());
+        assert!(value_to_image(&array([64], &[0.0; 64])).is_none());
+    }
+}
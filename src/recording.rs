@@ -1,9 +1,12 @@
 use std::{
-    collections::HashMap,
+    array,
+    collections::{HashMap, VecDeque},
     iter::Peekable,
+    mem,
     sync::{
-        LazyLock,
-        mpsc::{Receiver, SendError, Sender, channel},
+        Arc, LazyLock, Mutex, MutexGuard,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{Receiver, SendError, Sender, TrySendError, channel},
     },
     time::Duration,
 };
@@ -13,72 +16,366 @@ use indexmap::IndexSet;
 use rodio::{Source, buffer::SamplesBuffer, source::Repeat};
 use uiua::{NativeSys, SysBackend};
 
+use crate::performance::{PerformanceAction, PerformanceEvent};
+use crate::spectrum::{SPECTRUM_BLOCK_FRAMES, SpectrumTap};
+
 pub const CHANNEL_COUNT: u16 = 2;
 pub static SAMPLE_RATE: LazyLock<u32> = LazyLock::new(|| NativeSys.audio_sample_rate());
+const PREROLL_CAPTURE_TIMEOUT: Duration = Duration::from_secs(1);
+const STEM_BLOCK_LEN: usize = 4096;
+const METER_BLOCK_FRAMES: usize = 1024;
 
 pub fn new_mixer(
-    is_recording_main: bool,
+    main_recording_tx: Option<MainRecordingTx>,
     is_recording_secondary: bool,
+    preroll_length: usize,
 ) -> (MixerController, Mixer) {
-    let (event_tx, event_rx) = channel();
-    let (main_recording_tx, main_recording_rx) = channel();
+    let (command_tx, command_rx) = channel();
     let (secondary_recording_tx, secondary_recording_rx) = channel();
+    let (preroll_tx, preroll_rx) = channel();
+    let (hold_tx, hold_rx) = channel();
+    let (recycle_tx, recycled_stem_blocks) = channel();
+    let meter = Arc::<Meter>::default();
+    let status = Arc::<Mutex<PlaybackStatus>>::default();
     (
-        MixerController::new(
-            is_recording_main,
+        MixerController {
+            is_recording_main: main_recording_tx.is_some(),
             is_recording_secondary,
-            event_tx,
-            main_recording_rx,
+            command_tx,
             secondary_recording_rx,
-        ),
-        Mixer::new(
-            is_recording_main,
+            preroll_rx,
+            preroll_length,
+            spare_preroll: Some(PrerollRing::new(preroll_length)),
+            hold_rx,
+            held_sources: Mutex::default(),
+            meter: meter.clone(),
+            status: status.clone(),
+        },
+        Mixer {
+            command_rx,
+            regular_sources: Vec::default(),
+            held_sources: HashMap::default(),
+            played_sources: Vec::default(),
+            streams: Vec::default(),
             is_recording_secondary,
-            event_rx,
             main_recording_tx,
+            stem_block: StemBlock::new(recycle_tx),
+            recycled_stem_blocks,
             secondary_recording_tx,
-        ),
+            secondary_recording_len: 0,
+            preroll: PrerollRing::new(preroll_length),
+            preroll_tx,
+            hold_tx,
+            schedule: VecDeque::default(),
+            schedule_frame: 0,
+            sample_index: 0,
+            meter,
+            meter_block: MeterBlock::default(),
+            status,
+            tap: None,
+        },
     )
 }
 
+/// The mixer's output level, published a block of `METER_BLOCK_FRAMES` frames at a
+/// time so that reading it never holds up the audio. Peaks are held until they're read,
+/// so a reader that's slower than the blocks doesn't miss any.
+#[derive(Default)]
+pub struct Meter {
+    // f32 bits, since there's no atomic f32
+    peaks: [AtomicU32; CHANNEL_COUNT as usize],
+    rms: [AtomicU32; CHANNEL_COUNT as usize],
+    clipped: AtomicBool,
+}
+
+impl Meter {
+    /// The peak level of each channel since the last call, and its RMS level over the
+    /// last block. Above 1 means the channel clipped.
+    pub fn take_levels(&self) -> [(f32, f32); CHANNEL_COUNT as usize] {
+        array::from_fn(|c| {
+            (
+                f32::from_bits(self.peaks[c].swap(0, Ordering::Relaxed)),
+                f32::from_bits(self.rms[c].load(Ordering::Relaxed)),
+            )
+        })
+    }
+
+    /// Whether the output has clipped since the last call.
+    pub fn take_clipped(&self) -> bool {
+        self.clipped.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// What the mixer is playing, published along with the meter's levels. The mixer skips
+/// publishing rather than wait if the lock is taken.
+#[derive(Clone, Default)]
+pub struct PlaybackStatus {
+    pub voices: usize,
+    /// How far each held key is through its sound, from 0 to 1
+    pub held_positions: Vec<(KeyCode, f32)>,
+    /// Frames this mixer has sent to the secondary recording
+    pub secondary_recording_frames: u64,
+}
+
+/// A held key's sound, which repeats until the key is released.
+pub struct HeldSource {
+    samples: Peekable<Repeat<SamplesBuffer<f32>>>,
+    len: usize,
+    position: usize,
+}
+
+impl HeldSource {
+    /// Measures the sound up front, so that the audio thread doesn't have to.
+    pub fn new(source: SamplesBuffer<f32>) -> Self {
+        HeldSource {
+            len: source.clone().count(),
+            samples: source.repeat_infinite().peekable(),
+            position: 0,
+        }
+    }
+}
+
+/// The levels of the block the mixer is partway through.
+#[derive(Default)]
+struct MeterBlock {
+    peaks: [f32; CHANNEL_COUNT as usize],
+    sums_of_squares: [f32; CHANNEL_COUNT as usize],
+    frames: usize,
+}
+
+/// Mono blocks of the mixer's output, sent off for analysis.
+struct Tap {
+    tap: SpectrumTap,
+    block: Vec<f32>,
+    frame_sum: f32,
+}
+
+impl Tap {
+    fn new(tap: SpectrumTap) -> Self {
+        Tap {
+            block: tap
+                .recycled
+                .try_recv()
+                .unwrap_or_else(|_| Vec::with_capacity(SPECTRUM_BLOCK_FRAMES)),
+            tap,
+            frame_sum: 0.0,
+        }
+    }
+}
+
+/// Converts a frame count at the mixer's sample rate into a duration.
+pub fn frames_to_duration(frames: u64) -> Duration {
+    #[allow(clippy::cast_precision_loss)]
+    Duration::from_secs_f64(frames as f64 / f64::from(*SAMPLE_RATE))
+}
+
+/// Converts a duration into a sample count for the mixer's output format, or `None` if
+/// there would be too many samples to count.
+pub fn duration_to_samples(duration: Duration) -> Option<usize> {
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let frames = (duration.as_secs_f64() * f64::from(*SAMPLE_RATE)) as usize;
+    frames.checked_mul(CHANNEL_COUNT as usize)
+}
+
+/// Where the mixer sends a main recording as it plays.
+#[derive(Clone)]
+pub struct MainRecordingTx {
+    pub mix: Sender<f32>,
+    pub stems: Option<Sender<StemBlock>>,
+    pub events: Sender<PerformanceEvent>,
+    /// Frames recorded so far, shared so the count carries over when audio is
+    /// reinitialized mid-recording
+    pub frames: Arc<AtomicU64>,
+}
+
+/// A stretch of each key's output before mixing, all the same length. Keys that were
+/// silent for the whole stretch are left out.
+pub struct StemBlock {
+    pub len: usize,
+    pub stems: HashMap<KeyCode, Vec<f32>>,
+    // Zeroed buffers for keys that start playing, so the mixer doesn't have to allocate
+    spare: Vec<Vec<f32>>,
+    recycle_tx: Sender<StemBlock>,
+}
+
+impl StemBlock {
+    fn new(recycle_tx: Sender<StemBlock>) -> Self {
+        StemBlock {
+            len: 0,
+            stems: HashMap::new(),
+            spare: Vec::new(),
+            recycle_tx,
+        }
+    }
+
+    fn add(&mut self, key: KeyCode, sample: f32) {
+        let spare = &mut self.spare;
+        self.stems
+            .entry(key)
+            .or_insert_with(|| spare.pop().unwrap_or_else(|| vec![0.0; STEM_BLOCK_LEN]))
+            [self.len] += sample;
+    }
+
+    /// Empties the block, keeping its buffers for reuse.
+    fn clear(&mut self) {
+        for (_, mut samples) in self.stems.drain() {
+            samples.fill(0.0);
+            self.spare.push(samples);
+        }
+        self.len = 0;
+    }
+
+    /// Clears the block and hands it back to the mixer that sent it, if it's still
+    /// around, so that it's freed and refilled off the audio thread.
+    pub fn recycle(mut self) {
+        self.clear();
+        let _ = self.recycle_tx.clone().send(self);
+    }
+}
+
+/// The latest samples the mixer has output, as many as fit. Allocated up front, so that
+/// keeping them never allocates on the audio thread.
+pub struct PrerollRing {
+    samples: Vec<f32>,
+    start: usize,
+    len: usize,
+}
+
+impl PrerollRing {
+    pub fn new(capacity: usize) -> Self {
+        PrerollRing {
+            samples: vec![0.0; capacity],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Adds a sample, dropping the oldest one if the ring is full.
+    fn push(&mut self, sample: f32) {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return;
+        }
+        if self.len < capacity {
+            self.samples[(self.start + self.len) % capacity] = sample;
+            self.len += 1;
+        } else {
+            self.samples[self.start] = sample;
+            self.start = (self.start + 1) % capacity;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = f32> + '_ {
+        (0..self.len).map(|i| self.samples[(self.start + i) % self.capacity()])
+    }
+
+    /// Adds the samples of `other` after these ones.
+    fn extend_from(&mut self, other: &PrerollRing) {
+        for sample in other.iter() {
+            self.push(sample);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+pub enum ScheduledAction {
+    /// The sound is scaled by the gain as it plays
+    Press(KeyCode, SamplesBuffer<f32>, f32),
+    Hold(KeyCode, HeldSource),
+    Release(KeyCode),
+    Stop,
+}
+
+/// An action for the mixer to take a number of frames after the schedule it's part of
+/// was received.
+pub struct ScheduledEvent {
+    pub frame: u64,
+    pub action: ScheduledAction,
+}
+
+/// Samples generated as they're played, which might never end.
+pub type Stream = Box<dyn Iterator<Item = f32> + Send>;
+
 pub enum MixerCommand {
-    Source(SamplesBuffer<f32>),
-    ToggleHold(KeyCode, SamplesBuffer<f32>),
-    StartMainRecording,
+    /// The sound is scaled by the gain as it plays, so it doesn't have to be copied
+    Source(KeyCode, SamplesBuffer<f32>, f32),
+    /// A sound that doesn't belong to a key, so it isn't logged or given a stem
+    Play(SamplesBuffer<f32>),
+    /// Like `Play`, but keeps going until playback is stopped or the stream ends
+    Stream(Stream),
+    ToggleHold(KeyCode, HeldSource),
+    StartMainRecording(MainRecordingTx),
     StartSecondaryRecording,
     StopMainRecording,
     StopSecondaryRecording,
     StopPlayback,
+    /// Hands the mixer an empty ring to keep the pre-roll in, in exchange for its full one
+    SwapPreroll(PrerollRing),
+    /// Hands back a full ring, which the mixer adds what it's played since the swap to and
+    /// keeps. The ring it was keeping the pre-roll in meanwhile is sent back empty
+    RestorePreroll(PrerollRing),
+    /// Replaces whatever was scheduled before; events must be sorted by frame
+    Schedule(Vec<ScheduledEvent>),
+    /// Sends the output, mixed down to mono, in blocks of `SPECTRUM_BLOCK_FRAMES` frames.
+    /// Blocks are dropped rather than wait when the receiver falls behind
+    SetTap(SpectrumTap),
+    RemoveTap,
+}
+
+/// A change to which keys the mixer holds, reported back so that holds and releases
+/// from a schedule reach the controller too.
+enum HoldChange {
+    Held(KeyCode),
+    Released(KeyCode),
+    ReleasedAll,
 }
 
 pub struct MixerController {
     is_recording_main: bool,
     is_recording_secondary: bool,
     command_tx: Sender<MixerCommand>,
-    main_recording_rx: Receiver<f32>,
     secondary_recording_rx: Receiver<f32>,
-    held_sources: IndexSet<KeyCode>,
+    preroll_rx: Receiver<PrerollRing>,
+    preroll_length: usize,
+    spare_preroll: Option<PrerollRing>,
+    hold_rx: Receiver<HoldChange>,
+    held_sources: Mutex<IndexSet<KeyCode>>,
+    meter: Arc<Meter>,
+    status: Arc<Mutex<PlaybackStatus>>,
 }
 
 impl MixerController {
-    fn new(
-        is_recording_main: bool,
-        is_recording_secondary: bool,
-        command_tx: Sender<MixerCommand>,
-        main_recording_rx: Receiver<f32>,
-        secondary_recording_rx: Receiver<f32>,
-    ) -> Self {
-        MixerController {
-            is_recording_main,
-            is_recording_secondary,
-            command_tx,
-            main_recording_rx,
-            secondary_recording_rx,
-            held_sources: IndexSet::default(),
-        }
+    pub fn add(
+        &self,
+        key: KeyCode,
+        source: SamplesBuffer<f32>,
+    ) -> Result<(), SendError<MixerCommand>> {
+        self.add_with_gain(key, source, 1.0)
     }
-    pub fn add(&self, source: SamplesBuffer<f32>) -> Result<(), SendError<MixerCommand>> {
-        self.command_tx.send(MixerCommand::Source(source))
+    pub fn add_with_gain(
+        &self,
+        key: KeyCode,
+        source: SamplesBuffer<f32>,
+        gain: f32,
+    ) -> Result<(), SendError<MixerCommand>> {
+        self.command_tx
+            .send(MixerCommand::Source(key, source, gain))
+    }
+    /// Returns a sender for playing sounds from elsewhere, like the Uiua backend.
+    pub fn command_sender(&self) -> Sender<MixerCommand> {
+        self.command_tx.clone()
     }
     pub fn toggle_hold(
         &mut self,
@@ -86,17 +383,21 @@ impl MixerController {
         source: SamplesBuffer<f32>,
     ) -> Result<(), SendError<MixerCommand>> {
         self.command_tx
-            .send(MixerCommand::ToggleHold(key, source))?;
-        if self.held_sources.contains(&key) {
-            self.held_sources.shift_remove(&key);
-        } else {
-            self.held_sources.insert(key);
+            .send(MixerCommand::ToggleHold(key, HeldSource::new(source)))?;
+        // The mixer reports what it actually did, but that takes a moment to arrive
+        let mut held = self.held_sources();
+        if !held.shift_remove(&key) {
+            held.insert(key);
         }
         Ok(())
     }
 
-    pub fn start_main_recording(&mut self) -> Result<(), SendError<MixerCommand>> {
-        self.command_tx.send(MixerCommand::StartMainRecording)?;
+    pub fn start_main_recording(
+        &mut self,
+        recording_tx: MainRecordingTx,
+    ) -> Result<(), SendError<MixerCommand>> {
+        self.command_tx
+            .send(MixerCommand::StartMainRecording(recording_tx))?;
         self.is_recording_main = true;
         Ok(())
     }
@@ -106,23 +407,73 @@ impl MixerController {
         self.is_recording_secondary = true;
         Ok(())
     }
+    pub fn schedule(&self, events: Vec<ScheduledEvent>) -> Result<(), SendError<MixerCommand>> {
+        self.command_tx.send(MixerCommand::Schedule(events))
+    }
+    pub fn set_tap(&self, tap: SpectrumTap) -> Result<(), SendError<MixerCommand>> {
+        self.command_tx.send(MixerCommand::SetTap(tap))
+    }
+    pub fn remove_tap(&self) -> Result<(), SendError<MixerCommand>> {
+        self.command_tx.send(MixerCommand::RemoveTap)
+    }
     pub fn stop_playback(&mut self) -> Result<(), SendError<MixerCommand>> {
         self.command_tx.send(MixerCommand::StopPlayback)?;
-        self.held_sources.clear();
+        self.held_sources().clear();
         Ok(())
     }
 
-    pub fn get_main_recording(&mut self) -> Vec<f32> {
-        self.main_recording_rx.try_iter().collect()
+    /// Resizes the mixer's rolling buffer, keeping as much of what's in it as fits.
+    /// Returns `None` if the mixer could not be reached or did not respond in time.
+    pub fn set_preroll_length(&mut self, length: usize) -> Option<()> {
+        if length == self.preroll_length {
+            return Some(());
+        }
+        let old = self.take_preroll(length)?;
+        let mut ring = PrerollRing::new(length);
+        ring.extend_from(&old);
+        self.preroll_length = length;
+        self.restore_preroll(ring)
+    }
+    /// Returns the contents of the mixer's rolling buffer, or `None` if the mixer
+    /// could not be reached or did not respond in time.
+    pub fn capture_preroll(&mut self) -> Option<Vec<f32>> {
+        let ring = self.take_preroll(self.preroll_length)?;
+        let samples = ring.iter().collect();
+        self.restore_preroll(ring)?;
+        Some(samples)
+    }
+
+    // The mixer keeps going in an empty ring of `length` while its full one is copied
+    // here, off the audio thread
+    fn take_preroll(&mut self, length: usize) -> Option<PrerollRing> {
+        // Rings that arrived after an earlier call gave up on them
+        while self.preroll_rx.try_recv().is_ok() {}
+        let spare = self
+            .spare_preroll
+            .take()
+            .filter(|r| r.capacity() == length)
+            .unwrap_or_else(|| PrerollRing::new(length));
+        self.command_tx
+            .send(MixerCommand::SwapPreroll(spare))
+            .ok()?;
+        self.preroll_rx.recv_timeout(PREROLL_CAPTURE_TIMEOUT).ok()
     }
+    fn restore_preroll(&mut self, ring: PrerollRing) -> Option<()> {
+        self.command_tx
+            .send(MixerCommand::RestorePreroll(ring))
+            .ok()?;
+        self.spare_preroll = Some(self.preroll_rx.recv_timeout(PREROLL_CAPTURE_TIMEOUT).ok()?);
+        Some(())
+    }
+
     pub fn get_secondary_recording(&mut self) -> Vec<f32> {
         self.secondary_recording_rx.try_iter().collect()
     }
 
-    pub fn stop_main_recording(&mut self) -> Result<Vec<f32>, SendError<MixerCommand>> {
+    pub fn stop_main_recording(&mut self) -> Result<(), SendError<MixerCommand>> {
         self.command_tx.send(MixerCommand::StopMainRecording)?;
         self.is_recording_main = false;
-        Ok(self.get_main_recording())
+        Ok(())
     }
     pub fn stop_secondary_recording(&mut self) -> Result<Vec<f32>, SendError<MixerCommand>> {
         self.command_tx.send(MixerCommand::StopSecondaryRecording)?;
@@ -130,12 +481,37 @@ impl MixerController {
         Ok(self.get_secondary_recording())
     }
 
-    pub fn held_sources(&self) -> &IndexSet<KeyCode> {
-        &self.held_sources
+    /// The keys being held, whether by the player or by a schedule.
+    pub fn held_sources(&self) -> MutexGuard<'_, IndexSet<KeyCode>> {
+        let mut held = self
+            .held_sources
+            .lock()
+            .expect("held sources should not be poisoned");
+        for change in self.hold_rx.try_iter() {
+            match change {
+                HoldChange::Held(k) => {
+                    held.insert(k);
+                }
+                HoldChange::Released(k) => {
+                    held.shift_remove(&k);
+                }
+                HoldChange::ReleasedAll => held.clear(),
+            }
+        }
+        held
     }
     pub fn is_recording_main(&self) -> bool {
         self.is_recording_main
     }
+    pub fn meter(&self) -> &Meter {
+        &self.meter
+    }
+    pub fn playback_status(&self) -> PlaybackStatus {
+        self.status
+            .lock()
+            .expect("playback status should not be poisoned")
+            .clone()
+    }
     pub fn is_recording_secondary(&self) -> bool {
         self.is_recording_secondary
     }
@@ -143,66 +519,267 @@ impl MixerController {
 
 pub struct Mixer {
     command_rx: Receiver<MixerCommand>,
-    regular_sources: Vec<Peekable<SamplesBuffer<f32>>>,
-    held_sources: HashMap<KeyCode, Peekable<Repeat<SamplesBuffer<f32>>>>,
-    is_recording_main: bool,
+    regular_sources: Vec<(KeyCode, f32, Peekable<SamplesBuffer<f32>>)>,
+    held_sources: HashMap<KeyCode, HeldSource>,
+    played_sources: Vec<Peekable<SamplesBuffer<f32>>>,
+    streams: Vec<Peekable<Stream>>,
     is_recording_secondary: bool,
-    main_recording_tx: Sender<f32>,
+    main_recording_tx: Option<MainRecordingTx>,
+    stem_block: StemBlock,
+    recycled_stem_blocks: Receiver<StemBlock>,
     secondary_recording_tx: Sender<f32>,
+    secondary_recording_len: u64,
+    preroll: PrerollRing,
+    preroll_tx: Sender<PrerollRing>,
+    hold_tx: Sender<HoldChange>,
+    schedule: VecDeque<ScheduledEvent>,
+    schedule_frame: u64,
+    sample_index: u64,
+    meter: Arc<Meter>,
+    meter_block: MeterBlock,
+    status: Arc<Mutex<PlaybackStatus>>,
+    tap: Option<Tap>,
 }
 
 impl Mixer {
-    fn new(
-        is_recording_main: bool,
-        is_recording_secondary: bool,
-        event_rx: Receiver<MixerCommand>,
-        main_recording_tx: Sender<f32>,
-        secondary_recording_tx: Sender<f32>,
-    ) -> Self {
-        Mixer {
-            command_rx: event_rx,
-            regular_sources: Vec::default(),
-            held_sources: HashMap::default(),
-            is_recording_main,
-            is_recording_secondary,
-            main_recording_tx,
-            secondary_recording_tx,
+    /// Whether anything besides held keys and streams is still playing or waiting to be
+    /// played.
+    pub fn is_busy(&self) -> bool {
+        !self.schedule.is_empty()
+            || !self.regular_sources.is_empty()
+            || !self.played_sources.is_empty()
+    }
+
+    fn meter_sample(&mut self, channel: usize, sample: f32) {
+        let block = &mut self.meter_block;
+        block.peaks[channel] = block.peaks[channel].max(sample.abs());
+        block.sums_of_squares[channel] += sample * sample;
+        if sample.abs() > 1.0 {
+            self.meter.clipped.store(true, Ordering::Relaxed);
+        }
+
+        if channel + 1 < CHANNEL_COUNT as usize {
+            return;
+        }
+        block.frames += 1;
+        if block.frames == METER_BLOCK_FRAMES {
+            let block = mem::take(&mut self.meter_block);
+            for c in 0..CHANNEL_COUNT as usize {
+                #[allow(clippy::cast_precision_loss)]
+                let rms = (block.sums_of_squares[c] / block.frames as f32).sqrt();
+                // Non-negative floats order the same way as their bits
+                self.meter.peaks[c].fetch_max(block.peaks[c].to_bits(), Ordering::Relaxed);
+                self.meter.rms[c].store(rms.to_bits(), Ordering::Relaxed);
+            }
+            self.publish_status();
         }
     }
 
-    fn handle_events(&mut self) {
-        self.command_rx.try_iter().for_each(|e| match e {
-            MixerCommand::Source(s) => {
-                self.regular_sources.push(s.peekable());
+    fn tap_sample(&mut self, channel: usize, sample: f32) {
+        let Some(tap) = &mut self.tap else {
+            return;
+        };
+        tap.frame_sum += sample;
+        if channel + 1 < CHANNEL_COUNT as usize {
+            return;
+        }
+        tap.block.push(tap.frame_sum / f32::from(CHANNEL_COUNT));
+        tap.frame_sum = 0.0;
+        if tap.block.len() == SPECTRUM_BLOCK_FRAMES {
+            tap.block = match tap.tap.blocks.try_send(mem::take(&mut tap.block)) {
+                // Blocks only need allocating if the analyzer hasn't given any back
+                Ok(()) => tap
+                    .tap
+                    .recycled
+                    .try_recv()
+                    .unwrap_or_else(|_| Vec::with_capacity(SPECTRUM_BLOCK_FRAMES)),
+                // The analyzer is behind, so this block is dropped and its buffer refilled
+                Err(TrySendError::Full(mut block)) => {
+                    block.clear();
+                    block
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    self.tap = None;
+                    return;
+                }
+            };
+        }
+    }
+
+    fn publish_status(&self) {
+        let Ok(mut status) = self.status.try_lock() else {
+            return;
+        };
+        status.voices = self.regular_sources.len()
+            + self.held_sources.len()
+            + self.played_sources.len()
+            + self.streams.len();
+        // Refilled in place, so the audio thread only allocates when more keys are held
+        // than ever before
+        status.held_positions.clear();
+        #[allow(clippy::cast_precision_loss)]
+        status.held_positions.extend(
+            self.held_sources
+                .iter()
+                .map(|(k, s)| (*k, s.position as f32 / s.len.max(1) as f32)),
+        );
+        status.secondary_recording_frames = self.secondary_recording_len / u64::from(CHANNEL_COUNT);
+    }
+
+    fn is_recording_stems(&self) -> bool {
+        self.main_recording_tx
+            .as_ref()
+            .is_some_and(|r| r.stems.is_some())
+    }
+
+    fn log_event(&self, action: PerformanceAction) {
+        if let Some(r) = &self.main_recording_tx {
+            let _ = r.events.send(PerformanceEvent {
+                frame: r.frames.load(Ordering::Relaxed),
+                action,
+            });
+        }
+    }
+
+    fn flush_stems(&mut self) {
+        let Some(tx) = self
+            .main_recording_tx
+            .as_ref()
+            .and_then(|r| r.stems.as_ref())
+            .filter(|_| self.stem_block.len > 0)
+        else {
+            self.stem_block.clear();
+            return;
+        };
+        // Blocks only need allocating until the first ones come back from the writer
+        let next = self
+            .recycled_stem_blocks
+            .try_recv()
+            .unwrap_or_else(|_| StemBlock::new(self.stem_block.recycle_tx.clone()));
+        let _ = tx.send(mem::replace(&mut self.stem_block, next));
+    }
+
+    fn hold(&mut self, key: KeyCode, source: HeldSource) {
+        self.held_sources.insert(key, source);
+        self.log_event(PerformanceAction::Hold(key));
+        let _ = self.hold_tx.send(HoldChange::Held(key));
+    }
+
+    fn release(&mut self, key: KeyCode) {
+        self.held_sources.remove(&key);
+        self.log_event(PerformanceAction::Release(key));
+        let _ = self.hold_tx.send(HoldChange::Released(key));
+    }
+
+    fn stop_voices(&mut self) {
+        self.log_event(PerformanceAction::Stop);
+        let _ = self.hold_tx.send(HoldChange::ReleasedAll);
+        self.regular_sources.clear();
+        self.held_sources.clear();
+        self.played_sources.clear();
+        self.streams.clear();
+        self.regular_sources.shrink_to_fit();
+        self.held_sources.shrink_to_fit();
+        self.played_sources.shrink_to_fit();
+        self.streams.shrink_to_fit();
+    }
+
+    fn handle_command(&mut self, command: MixerCommand) {
+        match command {
+            MixerCommand::Source(k, s, gain) => {
+                self.regular_sources.push((k, gain, s.peekable()));
+                self.log_event(PerformanceAction::Press(k, gain));
+            }
+            MixerCommand::Play(s) => {
+                self.played_sources.push(s.peekable());
+            }
+            MixerCommand::Stream(s) => {
+                self.streams.push(s.peekable());
             }
-            MixerCommand::ToggleHold(k, s) =>
-            {
-                #[allow(clippy::map_entry)]
+            MixerCommand::ToggleHold(k, s) => {
                 if self.held_sources.contains_key(&k) {
-                    self.held_sources.remove(&k);
+                    self.release(k);
                 } else {
-                    self.held_sources.insert(k, s.repeat_infinite().peekable());
+                    self.hold(k, s);
                 }
             }
-            MixerCommand::StartMainRecording => {
-                self.is_recording_main = true;
+            MixerCommand::StartMainRecording(tx) => {
+                self.main_recording_tx = Some(tx);
+                // Otherwise keys held since before the recording would be missing from
+                // its replay
+                for &k in self.held_sources.keys() {
+                    self.log_event(PerformanceAction::Hold(k));
+                }
             }
             MixerCommand::StartSecondaryRecording => {
                 self.is_recording_secondary = true;
+                self.secondary_recording_len = 0;
             }
             MixerCommand::StopPlayback => {
-                self.regular_sources.clear();
-                self.held_sources.clear();
-                self.regular_sources.shrink_to_fit();
-                self.held_sources.shrink_to_fit();
+                self.schedule.clear();
+                self.stop_voices();
             }
             MixerCommand::StopMainRecording => {
-                self.is_recording_main = false;
+                self.flush_stems();
+                // Dropping the senders is what lets the recording finish
+                self.main_recording_tx = None;
             }
             MixerCommand::StopSecondaryRecording => {
                 self.is_recording_secondary = false;
             }
-        });
+            MixerCommand::SwapPreroll(ring) => {
+                let _ = self.preroll_tx.send(mem::replace(&mut self.preroll, ring));
+            }
+            MixerCommand::RestorePreroll(mut ring) => {
+                // Only what's been played since the swap, so this is quick
+                ring.extend_from(&self.preroll);
+                let mut spare = mem::replace(&mut self.preroll, ring);
+                spare.clear();
+                let _ = self.preroll_tx.send(spare);
+            }
+            MixerCommand::Schedule(events) => {
+                self.schedule = events.into();
+                self.schedule_frame = 0;
+            }
+            MixerCommand::SetTap(tap) => {
+                self.tap = Some(Tap::new(tap));
+            }
+            MixerCommand::RemoveTap => {
+                self.tap = None;
+            }
+        }
+    }
+
+    fn handle_scheduled(&mut self, action: ScheduledAction) {
+        match action {
+            ScheduledAction::Press(k, s, gain) => {
+                self.regular_sources.push((k, gain, s.peekable()));
+                self.log_event(PerformanceAction::Press(k, gain));
+            }
+            ScheduledAction::Hold(k, s) => self.hold(k, s),
+            ScheduledAction::Release(k) => self.release(k),
+            ScheduledAction::Stop => {
+                self.stop_voices();
+            }
+        }
+    }
+
+    fn handle_events(&mut self) {
+        while let Ok(command) = self.command_rx.try_recv() {
+            self.handle_command(command);
+        }
+
+        while self
+            .schedule
+            .front()
+            .is_some_and(|e| e.frame <= self.schedule_frame)
+        {
+            let event = self.schedule.pop_front().unwrap();
+            self.handle_scheduled(event.action);
+        }
+        if !self.schedule.is_empty() {
+            self.schedule_frame += 1;
+        }
     }
 }
 
@@ -210,36 +787,92 @@ impl Iterator for Mixer {
     type Item = f32;
 
     fn next(&mut self) -> Option<f32> {
-        self.handle_events();
+        // Commands only take effect between frames so that sources, recordings and the
+        // pre-roll buffer always start on the first channel
+        if self.sample_index.is_multiple_of(u64::from(CHANNEL_COUNT)) {
+            self.handle_events();
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let channel = (self.sample_index % u64::from(CHANNEL_COUNT)) as usize;
+        self.sample_index += 1;
 
-        self.regular_sources.retain_mut(|x| x.peek().is_some());
-        self.held_sources.retain(|_, v| v.peek().is_some());
+        self.regular_sources
+            .retain_mut(|(_, _, x)| x.peek().is_some());
+        self.held_sources.retain(|_, v| v.samples.peek().is_some());
+        self.played_sources.retain_mut(|s| s.peek().is_some());
+        self.streams.retain_mut(|s| s.peek().is_some());
 
-        let sample = self
+        let is_recording_stems = self.is_recording_stems();
+        let voices = self
             .regular_sources
             .iter_mut()
-            .map(|s| {
-                s.next()
-                    .expect("Empty non-held sources should have been removed")
+            .map(|(k, gain, s)| {
+                let x = s
+                    .next()
+                    .expect("Empty non-held sources should have been removed");
+                (*k, x * *gain)
             })
-            .chain(self.held_sources.values_mut().map(|s| {
-                s.next()
-                    .expect("Empty held sources should have been removed")
-            }))
-            .sum::<f32>()
-            .clamp(-1.0, 1.0);
+            .chain(self.held_sources.iter_mut().map(|(k, s)| {
+                let x = s
+                    .samples
+                    .next()
+                    .expect("Empty held sources should have been removed");
+                s.position = (s.position + 1) % s.len;
+                (*k, x)
+            }));
 
-        if self.is_recording_main {
-            self.main_recording_tx.send(sample).unwrap();
+        let mut sample = 0.0;
+        for (key, x) in voices {
+            sample += x;
+            if is_recording_stems {
+                self.stem_block.add(key, x);
+            }
+        }
+        for s in &mut self.played_sources {
+            sample += s
+                .next()
+                .expect("Empty played sources should have been removed");
+        }
+        for s in &mut self.streams {
+            sample += s.next().expect("Ended streams should have been removed");
+        }
+        let unclamped = sample;
+        let sample = f32::clamp(sample, -1.0, 1.0);
+
+        if is_recording_stems {
+            self.stem_block.len += 1;
+            if self.stem_block.len == STEM_BLOCK_LEN {
+                self.flush_stems();
+            }
+        }
+        if let Some(tx) = &self.main_recording_tx {
+            if tx.mix.send(sample).is_err() {
+                self.main_recording_tx = None;
+            } else if self.sample_index.is_multiple_of(u64::from(CHANNEL_COUNT)) {
+                tx.frames.fetch_add(1, Ordering::Relaxed);
+            }
         }
         if self.is_recording_secondary {
+            self.secondary_recording_len += 1;
             self.secondary_recording_tx.send(sample).unwrap();
         }
+        self.preroll.push(sample);
+        self.tap_sample(channel, sample);
+        // Last so that the status it publishes includes this sample
+        self.meter_sample(channel, unclamped);
 
         Some(sample)
     }
 }
 
+impl Drop for Mixer {
+    fn drop(&mut self) {
+        // A recording can outlive the mixer when audio is reinitialized, so the stems
+        // have to stay in step with the mix
+        self.flush_stems();
+    }
+}
+
 impl Source for Mixer {
     fn current_frame_len(&self) -> Option<usize> {
         None
@@ -257,3 +890,475 @@ impl Source for Mixer {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const FRAME: usize = CHANNEL_COUNT as usize;
+
+    fn source(samples: &[f32]) -> SamplesBuffer<f32> {
+        SamplesBuffer::new(CHANNEL_COUNT, *SAMPLE_RATE, samples.to_vec())
+    }
+
+    fn pull(mixer: &mut Mixer, frames: usize) -> Vec<f32> {
+        mixer.by_ref().take(frames * FRAME).collect()
+    }
+
+    fn main_recording_tx() -> (MainRecordingTx, Receiver<f32>, Receiver<PerformanceEvent>) {
+        let (mix, mix_rx) = channel();
+        let (events, events_rx) = channel();
+        let tx = MainRecordingTx {
+            mix,
+            stems: None,
+            events,
+            frames: Arc::default(),
+        };
+        (tx, mix_rx, events_rx)
+    }
+
+    #[test]
+    fn sums_sources() {
+        let (controller, mut mixer) = new_mixer(None, false, 0);
+        controller
+            .add(KeyCode::Char('a'), source(&[0.25, -0.25, 0.5, 0.5]))
+            .unwrap();
+        controller
+            .add(KeyCode::Char('b'), source(&[0.25, 0.25]))
+            .unwrap();
+
+        assert_eq!(pull(&mut mixer, 3), [0.5, 0.0, 0.5, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn scales_sources_by_their_gain() {
+        let (controller, mut mixer) = new_mixer(None, false, 0);
+        controller
+            .add_with_gain(KeyCode::Char('a'), source(&[0.5, -1.0]), 0.5)
+            .unwrap();
+
+        assert_eq!(pull(&mut mixer, 1), [0.25, -0.5]);
+    }
+
+    #[test]
+    fn clamps_sum() {
+        let (controller, mut mixer) = new_mixer(None, false, 0);
+        for key in ['a', 'b'] {
+            controller
+                .add(KeyCode::Char(key), source(&[0.75, -0.75]))
+                .unwrap();
+        }
+
+        assert_eq!(pull(&mut mixer, 1), [1.0, -1.0]);
+    }
+
+    #[test]
+    fn toggling_hold_repeats_until_toggled_again() {
+        let (mut controller, mut mixer) = new_mixer(None, false, 0);
+        let key = KeyCode::Char('a');
+        controller.toggle_hold(key, source(&[0.5, 0.25])).unwrap();
+        assert_eq!(pull(&mut mixer, 3), [0.5, 0.25, 0.5, 0.25, 0.5, 0.25]);
+        assert!(controller.held_sources().contains(&key));
+
+        controller.toggle_hold(key, source(&[0.5, 0.25])).unwrap();
+        assert_eq!(pull(&mut mixer, 1), [0.0, 0.0]);
+        assert!(controller.held_sources().is_empty());
+    }
+
+    #[test]
+    fn stop_playback_silences_everything() {
+        let (mut controller, mut mixer) = new_mixer(None, false, 0);
+        controller
+            .add(KeyCode::Char('a'), source(&[0.5; 8]))
+            .unwrap();
+        controller
+            .toggle_hold(KeyCode::Char('b'), source(&[0.25, 0.25]))
+            .unwrap();
+        assert_eq!(pull(&mut mixer, 1), [0.75, 0.75]);
+
+        controller.stop_playback().unwrap();
+        assert_eq!(pull(&mut mixer, 2), [0.0; 4]);
+        assert!(controller.held_sources().is_empty());
+    }
+
+    #[test]
+    fn plays_sounds_and_streams_without_keys() {
+        let (mut controller, mut mixer) = new_mixer(None, false, 0);
+        let tx = controller.command_sender();
+        tx.send(MixerCommand::Play(source(&[0.5, 0.5]))).unwrap();
+        tx.send(MixerCommand::Stream(Box::new(std::iter::repeat(0.25))))
+            .unwrap();
+        assert_eq!(pull(&mut mixer, 2), [0.75, 0.75, 0.25, 0.25]);
+        assert!(!mixer.is_busy());
+
+        controller.stop_playback().unwrap();
+        assert_eq!(pull(&mut mixer, 1), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn meter_publishes_levels_and_clipping() {
+        let (controller, mut mixer) = new_mixer(None, false, 0);
+        let samples: Vec<f32> = [0.5, -0.25].repeat(METER_BLOCK_FRAMES);
+        controller
+            .add(KeyCode::Char('a'), source(&samples))
+            .unwrap();
+        pull(&mut mixer, METER_BLOCK_FRAMES - 1);
+        assert_eq!(controller.meter().take_levels(), [(0.0, 0.0); FRAME]);
+
+        pull(&mut mixer, 1);
+        assert_eq!(controller.meter().take_levels(), [(0.5, 0.5), (0.25, 0.25)]);
+        assert!(!controller.meter().take_clipped());
+
+        // A quieter block doesn't hide a peak that hasn't been read yet
+        controller
+            .add(KeyCode::Char('a'), source(&[0.75, 0.0]))
+            .unwrap();
+        pull(&mut mixer, 2 * METER_BLOCK_FRAMES);
+        assert_eq!(controller.meter().take_levels()[0].0, 0.75);
+        assert_eq!(controller.meter().take_levels()[0].0, 0.0);
+
+        for key in ['a', 'b'] {
+            controller
+                .add(KeyCode::Char(key), source(&[0.75, 0.0]))
+                .unwrap();
+        }
+        pull(&mut mixer, 1);
+        assert!(controller.meter().take_clipped());
+        assert!(!controller.meter().take_clipped());
+    }
+
+    #[test]
+    fn tap_sends_mono_blocks() {
+        let (controller, mut mixer) = new_mixer(None, false, 0);
+        let (tx, blocks) = std::sync::mpsc::sync_channel(1);
+        let (recycle_tx, recycled) = std::sync::mpsc::sync_channel(2);
+        let buffers = [(); 2].map(|()| Vec::with_capacity(SPECTRUM_BLOCK_FRAMES));
+        let pointers = buffers.each_ref().map(Vec::as_ptr);
+        for buffer in buffers {
+            recycle_tx.send(buffer).unwrap();
+        }
+        controller
+            .set_tap(SpectrumTap {
+                blocks: tx,
+                recycled,
+            })
+            .unwrap();
+        controller
+            .add(KeyCode::Char('a'), source(&[0.5, 0.25]))
+            .unwrap();
+        pull(&mut mixer, SPECTRUM_BLOCK_FRAMES - 1);
+        assert!(blocks.try_recv().is_err());
+
+        pull(&mut mixer, 1);
+        let block = blocks.try_recv().unwrap();
+        assert_eq!(block.len(), SPECTRUM_BLOCK_FRAMES);
+        assert_eq!(block[..2], [0.375, 0.0]);
+        assert_eq!(block.as_ptr(), pointers[0]);
+
+        // A full channel drops blocks instead of holding up the mixer, and the buffers
+        // it was given are refilled rather than new ones made
+        pull(&mut mixer, 2 * SPECTRUM_BLOCK_FRAMES);
+        let block = blocks.try_recv().unwrap();
+        assert_eq!(block.as_ptr(), pointers[1]);
+        assert!(blocks.try_recv().is_err());
+
+        controller.remove_tap().unwrap();
+        pull(&mut mixer, 1);
+        assert!(blocks.recv().is_err());
+    }
+
+    #[test]
+    fn publishes_playback_status() {
+        let (mut controller, mut mixer) = new_mixer(None, false, 0);
+        let key = KeyCode::Char('a');
+        controller
+            .toggle_hold(key, source(&[0.0; 3 * FRAME]))
+            .unwrap();
+        controller.start_secondary_recording().unwrap();
+        pull(&mut mixer, METER_BLOCK_FRAMES);
+
+        let status = controller.playback_status();
+        assert_eq!(status.voices, 1);
+        assert_eq!(status.held_positions, [(key, 1.0 / 3.0)]);
+        assert_eq!(status.secondary_recording_frames, METER_BLOCK_FRAMES as u64);
+    }
+
+    #[test]
+    fn main_recording_covers_whole_frames_between_start_and_stop() {
+        let (mut controller, mut mixer) = new_mixer(None, false, 0);
+        controller
+            .add(
+                KeyCode::Char('a'),
+                source(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8]),
+            )
+            .unwrap();
+        mixer.next();
+
+        // Starting mid-frame takes effect at the start of the next frame
+        let (tx, mix_rx, _events_rx) = main_recording_tx();
+        let frames = tx.frames.clone();
+        controller.start_main_recording(tx).unwrap();
+        pull(&mut mixer, 2);
+        controller.stop_main_recording().unwrap();
+        pull(&mut mixer, 1);
+
+        assert_eq!(mix_rx.try_iter().collect::<Vec<_>>(), [0.3, 0.4, 0.5, 0.6]);
+        assert_eq!(frames.load(Ordering::Relaxed), 2);
+        assert!(!controller.is_recording_main());
+    }
+
+    #[test]
+    fn main_recording_logs_events() {
+        let (tx, _mix_rx, events_rx) = main_recording_tx();
+        let (mut controller, mut mixer) = new_mixer(Some(tx), false, 0);
+        let key = KeyCode::Char('a');
+        pull(&mut mixer, 1);
+        controller
+            .add_with_gain(key, source(&[0.5, 0.5]), 0.25)
+            .unwrap();
+        pull(&mut mixer, 1);
+        controller.toggle_hold(key, source(&[0.5, 0.5])).unwrap();
+        controller.toggle_hold(key, source(&[0.5, 0.5])).unwrap();
+        pull(&mut mixer, 1);
+        controller.stop_playback().unwrap();
+        pull(&mut mixer, 1);
+
+        let events: Vec<_> = events_rx.try_iter().collect();
+        assert_eq!(
+            events,
+            [
+                PerformanceEvent {
+                    frame: 1,
+                    action: PerformanceAction::Press(key, 0.25)
+                },
+                PerformanceEvent {
+                    frame: 2,
+                    action: PerformanceAction::Hold(key)
+                },
+                PerformanceEvent {
+                    frame: 2,
+                    action: PerformanceAction::Release(key)
+                },
+                PerformanceEvent {
+                    frame: 3,
+                    action: PerformanceAction::Stop
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn main_recording_logs_keys_already_held() {
+        let (mut controller, mut mixer) = new_mixer(None, false, 0);
+        let key = KeyCode::Char('a');
+        controller.toggle_hold(key, source(&[0.5, 0.5])).unwrap();
+        pull(&mut mixer, 2);
+
+        let (tx, _mix_rx, events_rx) = main_recording_tx();
+        controller.start_main_recording(tx).unwrap();
+        pull(&mut mixer, 1);
+        assert_eq!(
+            events_rx.try_iter().collect::<Vec<_>>(),
+            [PerformanceEvent {
+                frame: 0,
+                action: PerformanceAction::Hold(key)
+            }]
+        );
+    }
+
+    #[test]
+    fn stem_blocks_are_recycled() {
+        let (mut tx, _mix_rx, _events_rx) = main_recording_tx();
+        let (stems, stems_rx) = channel();
+        tx.stems = Some(stems);
+        let (controller, mut mixer) = new_mixer(Some(tx), false, 0);
+        let key = KeyCode::Char('a');
+        controller.add(key, source(&[0.5; 2 * FRAME])).unwrap();
+        pull(&mut mixer, STEM_BLOCK_LEN / FRAME);
+        let block = stems_rx.try_recv().unwrap();
+        assert_eq!(
+            block.stems[&key][..3 * FRAME],
+            [0.5, 0.5, 0.5, 0.5, 0.0, 0.0]
+        );
+        let buffer = block.stems[&key].as_ptr();
+        block.recycle();
+
+        // The mixer already had the next block when this one came back
+        pull(&mut mixer, STEM_BLOCK_LEN / FRAME);
+        assert!(stems_rx.try_recv().unwrap().stems.is_empty());
+
+        controller.add(key, source(&[0.25; FRAME])).unwrap();
+        pull(&mut mixer, STEM_BLOCK_LEN / FRAME);
+        let block = stems_rx.try_recv().unwrap();
+        assert_eq!(block.stems[&key].as_ptr(), buffer);
+        assert_eq!(block.stems[&key][..2 * FRAME], [0.25, 0.25, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn secondary_recording_covers_frames_between_start_and_stop() {
+        let (mut controller, mut mixer) = new_mixer(None, false, 0);
+        controller
+            .add(KeyCode::Char('a'), source(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]))
+            .unwrap();
+        pull(&mut mixer, 1);
+        controller.start_secondary_recording().unwrap();
+        pull(&mut mixer, 1);
+        let recording = controller.stop_secondary_recording().unwrap();
+        pull(&mut mixer, 1);
+
+        assert_eq!(recording, [0.3, 0.4]);
+        assert!(controller.get_secondary_recording().is_empty());
+    }
+
+    #[test]
+    fn preroll_keeps_latest_samples() {
+        let (controller, mut mixer) = new_mixer(None, false, 4 * FRAME);
+        controller
+            .add(KeyCode::Char('a'), source(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]))
+            .unwrap();
+        pull(&mut mixer, 3);
+
+        let send = |command| controller.command_tx.send(command).unwrap();
+        send(MixerCommand::SwapPreroll(PrerollRing::new(4 * FRAME)));
+        pull(&mut mixer, 1);
+        let ring = controller.preroll_rx.try_recv().unwrap();
+        assert_eq!(
+            ring.iter().collect::<Vec<_>>(),
+            [0.1, 0.2, 0.3, 0.4, 0.5, 0.6]
+        );
+
+        // What's played while the ring is away is added when it comes back
+        send(MixerCommand::RestorePreroll(ring));
+        pull(&mut mixer, 1);
+        let spare = controller.preroll_rx.try_recv().unwrap();
+        assert_eq!(spare.iter().count(), 0);
+        send(MixerCommand::SwapPreroll(spare));
+        pull(&mut mixer, 1);
+        assert_eq!(
+            controller
+                .preroll_rx
+                .try_recv()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            [0.3, 0.4, 0.5, 0.6, 0.0, 0.0, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn controller_captures_and_resizes_preroll() {
+        // Long enough that the silence played while waiting on the controller doesn't
+        // push the sound out
+        const LENGTH: usize = 1 << 17;
+        let (mut controller, mut mixer) = new_mixer(None, false, LENGTH);
+        let sound = [0.1, 0.2, 0.3, 0.4];
+        controller.add(KeyCode::Char('a'), source(&sound)).unwrap();
+        pull(&mut mixer, 2);
+
+        let mut run = |f: fn(&mut MixerController) -> Option<Vec<f32>>| {
+            thread::scope(|s| {
+                let running = s.spawn(|| f(&mut controller));
+                while !running.is_finished() {
+                    mixer.next();
+                }
+                running.join().unwrap().unwrap()
+            })
+        };
+        let nonzero = |samples: Vec<f32>| samples.into_iter().filter(|&x| x != 0.0).collect();
+        let captured: Vec<f32> = nonzero(run(|c| c.capture_preroll()));
+        let grown: Vec<f32> = nonzero(run(|c| {
+            c.set_preroll_length(2 * LENGTH)?;
+            c.capture_preroll()
+        }));
+        let shrunk = run(|c| {
+            c.set_preroll_length(FRAME)?;
+            c.capture_preroll()
+        });
+
+        assert_eq!(captured, sound);
+        assert_eq!(grown, sound);
+        assert_eq!(shrunk.len(), FRAME);
+    }
+
+    #[test]
+    fn preroll_ring_drops_oldest_samples() {
+        let mut ring = PrerollRing::new(3);
+        for x in [1.0, 2.0, 3.0, 4.0, 5.0] {
+            ring.push(x);
+        }
+        assert_eq!(ring.iter().collect::<Vec<_>>(), [3.0, 4.0, 5.0]);
+
+        let mut empty = PrerollRing::new(0);
+        empty.push(1.0);
+        empty.extend_from(&ring);
+        assert_eq!(empty.iter().count(), 0);
+    }
+
+    #[test]
+    fn schedule_plays_events_on_their_frames() {
+        let (controller, mut mixer) = new_mixer(None, false, 0);
+        let key = KeyCode::Char('a');
+        controller
+            .schedule(vec![
+                ScheduledEvent {
+                    frame: 1,
+                    action: ScheduledAction::Press(key, source(&[0.5, 0.5]), 1.0),
+                },
+                ScheduledEvent {
+                    frame: 2,
+                    action: ScheduledAction::Hold(key, HeldSource::new(source(&[0.25, 0.25]))),
+                },
+                ScheduledEvent {
+                    frame: 4,
+                    action: ScheduledAction::Release(key),
+                },
+            ])
+            .unwrap();
+
+        assert!(!mixer.is_busy());
+        assert_eq!(
+            pull(&mut mixer, 5),
+            [0.0, 0.0, 0.5, 0.5, 0.25, 0.25, 0.25, 0.25, 0.0, 0.0]
+        );
+        assert!(!mixer.is_busy());
+    }
+
+    #[test]
+    fn controller_follows_scheduled_holds() {
+        let (mut controller, mut mixer) = new_mixer(None, false, 0);
+        let key = KeyCode::Char('a');
+        controller
+            .schedule(vec![ScheduledEvent {
+                frame: 0,
+                action: ScheduledAction::Hold(key, HeldSource::new(source(&[0.25, 0.25]))),
+            }])
+            .unwrap();
+        pull(&mut mixer, 1);
+        assert!(controller.held_sources().contains(&key));
+
+        // Toggling a key the schedule holds releases it
+        controller.toggle_hold(key, source(&[0.5, 0.5])).unwrap();
+        assert!(controller.held_sources().is_empty());
+        assert_eq!(pull(&mut mixer, 1), [0.0, 0.0]);
+        assert!(controller.held_sources().is_empty());
+
+        controller
+            .schedule(vec![
+                ScheduledEvent {
+                    frame: 0,
+                    action: ScheduledAction::Hold(key, HeldSource::new(source(&[0.25, 0.25]))),
+                },
+                ScheduledEvent {
+                    frame: 1,
+                    action: ScheduledAction::Stop,
+                },
+            ])
+            .unwrap();
+        pull(&mut mixer, 1);
+        assert!(controller.held_sources().contains(&key));
+        pull(&mut mixer, 1);
+        assert!(controller.held_sources().is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use crossterm::event::KeyCode;
+use hound::WavWriter;
+
+use crate::disk_recording::float_wav_spec;
+use crate::midi::{is_midi_file, read_notes, schedule_notes};
+use crate::performance::{read_events, schedule_events};
+use crate::recording::{CHANNEL_COUNT, SAMPLE_RATE, new_mixer};
+use crate::uiua_extension::UiuaExtension;
+
+/// Plays an event log or MIDI file with the sounds in main.ua as fast as possible, without an audio
+/// device, and writes the result to a WAV file. Stops once every pressed key has
+/// finished playing; keys still held and streams still going at that point are cut off.
+pub fn render(events_path: &Path, output_path: &Path, allow_git: bool) -> anyhow::Result<()> {
+    let (mixer_controller, mut mixer) = new_mixer(None, false, 0);
+    let mut uiua_extension = UiuaExtension::new(allow_git);
+    // Sounds main.ua plays as it loads start along with the events
+    uiua_extension.connect_mixer(&mixer_controller);
+    uiua_extension.load()?;
+    let key_source = |k: KeyCode| uiua_extension.key_sources().get(&k).cloned();
+    let events = if is_midi_file(events_path) {
+        schedule_notes(
+            read_notes(events_path, *SAMPLE_RATE)?,
+            uiua_extension.note_keys(),
+            key_source,
+        )
+    } else {
+        schedule_events(read_events(events_path, *SAMPLE_RATE)?, key_source)
+    };
+
+    mixer_controller
+        .schedule(events)
+        .map_err(|_| anyhow!("could not schedule events"))?;
+
+    let mut writer = WavWriter::create(output_path, float_wav_spec())?;
+    loop {
+        for sample in mixer.by_ref().take(usize::from(CHANNEL_COUNT)) {
+            writer.write_sample(sample)?;
+        }
+        if !mixer.is_busy() {
+            break;
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use anyhow::{bail, ensure};
+use serde_json::{Value as Json, json};
+
+use crate::control::ControlCommand;
+use crate::disk_recording::Take;
+use crate::export::ExportOptions;
+use crate::uauauiua::Uauauiua;
+
+/// A line of a control script. The name in a save is everything after `main` or `uiua`,
+/// so it can have spaces in it.
+#[derive(Debug, PartialEq, Eq)]
+enum ScriptCommand {
+    Control(ControlCommand),
+    SaveMain { name: String, overwrite: bool },
+    SaveSecondary(String),
+    DiscardMain,
+    DiscardSecondary,
+    Status,
+}
+
+impl ScriptCommand {
+    fn parse(line: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        Ok(match words.as_slice() {
+            ["save", "main", name @ ..] => ScriptCommand::SaveMain {
+                name: name.join(" "),
+                overwrite: false,
+            },
+            ["overwrite", "main", name @ ..] => ScriptCommand::SaveMain {
+                name: name.join(" "),
+                overwrite: true,
+            },
+            ["save", "uiua", name @ ..] => ScriptCommand::SaveSecondary(name.join(" ")),
+            ["discard", "main"] => ScriptCommand::DiscardMain,
+            ["discard", "uiua"] => ScriptCommand::DiscardSecondary,
+            ["status"] => ScriptCommand::Status,
+            [] => bail!("empty command"),
+            words => ScriptCommand::Control(ControlCommand::from_words(words)?),
+        })
+    }
+}
+
+/// Runs control scripts against an instrument, keeping stopped recordings until they're
+/// saved or discarded like the TUI's prompts do.
+pub struct ScriptSession {
+    uauauiua: Uauauiua,
+    main_take: Option<Take>,
+    secondary_take: Option<Vec<f32>>,
+}
+
+impl ScriptSession {
+    pub fn new(uauauiua: Uauauiua) -> Self {
+        ScriptSession {
+            uauauiua,
+            main_take: None,
+            secondary_take: None,
+        }
+    }
+
+    fn run_control_command(&mut self, command: ControlCommand) -> anyhow::Result<()> {
+        match command {
+            ControlCommand::Press(key) => self.uauauiua.add_to_mixer(key, false)?,
+            ControlCommand::ToggleHold(key) => self.uauauiua.add_to_mixer(key, true)?,
+            ControlCommand::StopPlayback => self.uauauiua.stop_playback()?,
+            ControlCommand::StartMainRecording => {
+                ensure!(!self.uauauiua.is_recording_main(), "already recording main");
+                self.uauauiua.start_main_recording()?;
+            }
+            ControlCommand::StartSecondaryRecording => {
+                ensure!(
+                    !self.uauauiua.is_recording_secondary(),
+                    "already recording uiua"
+                );
+                self.uauauiua.start_secondary_recording()?;
+            }
+            ControlCommand::StopMainRecording => {
+                ensure!(self.uauauiua.is_recording_main(), "not recording main");
+                ensure!(
+                    self.main_take.is_none(),
+                    "save or discard the last main recording first"
+                );
+                self.main_take = Some(self.uauauiua.stop_main_recording()?);
+            }
+            ControlCommand::StopSecondaryRecording => {
+                ensure!(self.uauauiua.is_recording_secondary(), "not recording uiua");
+                ensure!(
+                    self.secondary_take.is_none(),
+                    "save or discard the last uiua recording first"
+                );
+                self.secondary_take = Some(self.uauauiua.stop_secondary_recording()?);
+            }
+            ControlCommand::Reload => self.uauauiua.load()?,
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, command: ScriptCommand) -> anyhow::Result<()> {
+        match command {
+            ScriptCommand::Control(c) => self.run_control_command(c)?,
+            ScriptCommand::SaveMain { name, overwrite } => {
+                let Some(take) = &self.main_take else {
+                    bail!("no main recording to save");
+                };
+                let options = ExportOptions::default();
+                let name = self.uauauiua.resolve_main_recording_name(&name)?;
+                ensure!(
+                    overwrite || !self.uauauiua.main_recording_exists(&name, options),
+                    "{name} already exists; use 'overwrite main {name}' to replace it"
+                );
+                self.uauauiua.save_main_recording(take, &name, options)?;
+                self.main_take = None;
+            }
+            ScriptCommand::SaveSecondary(name) => {
+                let Some(recording) = self.secondary_take.take() else {
+                    bail!("no uiua recording to save");
+                };
+                self.uauauiua.save_secondary_recording(&recording, &name);
+            }
+            ScriptCommand::DiscardMain => {
+                let Some(take) = &self.main_take else {
+                    bail!("no main recording to discard");
+                };
+                self.uauauiua.discard_main_recording(take)?;
+                self.main_take = None;
+            }
+            ScriptCommand::DiscardSecondary => {
+                ensure!(
+                    self.secondary_take.take().is_some(),
+                    "no uiua recording to discard"
+                );
+            }
+            ScriptCommand::Status => {}
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> Json {
+        json!({
+            "recording_main": self.uauauiua.is_recording_main(),
+            "recording_uiua": self.uauauiua.is_recording_secondary(),
+            "unsaved_main": self.main_take.is_some(),
+            "unsaved_uiua": self.secondary_take.is_some(),
+            "held": self
+                .uauauiua
+                .held_sources()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            "audio_warning": self.uauauiua.audio_warning(),
+        })
+    }
+
+    /// Runs one line of a script, describing how it went.
+    fn reply(&mut self, line: &str) -> Json {
+        match ScriptCommand::parse(line).and_then(|c| self.run(c)) {
+            Ok(()) => json!({ "ok": true, "status": self.status() }),
+            Err(e) => json!({ "ok": false, "error": e.to_string(), "status": self.status() }),
+        }
+    }
+
+    /// Replies to each line of `input` with a line of JSON until `input` runs out. Blank
+    /// lines are skipped.
+    pub fn serve(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            writeln!(output, "{}", self.reply(&line))?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Serves each connection to a Unix socket at `path` in turn, sharing one session
+    /// between them. A socket left behind by a session that was killed is replaced.
+    #[cfg(unix)]
+    pub fn serve_socket(&mut self, path: &Path) -> anyhow::Result<()> {
+        use std::io::BufReader;
+
+        let listener = SocketListener::bind(path)?;
+        for stream in listener.listener.incoming() {
+            let stream = stream?;
+            // A client hanging up mid-reply shouldn't end the session
+            let _ = self.serve(BufReader::new(&stream), &stream);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn serve_socket(&mut self, _path: &Path) -> anyhow::Result<()> {
+        bail!("control sockets are only available on Unix")
+    }
+}
+
+/// A listening Unix socket that's removed again when it's dropped.
+#[cfg(unix)]
+struct SocketListener {
+    listener: std::os::unix::net::UnixListener,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl SocketListener {
+    fn bind(path: &Path) -> anyhow::Result<Self> {
+        use std::{
+            fs,
+            os::unix::{
+                fs::FileTypeExt,
+                net::{UnixListener, UnixStream},
+            },
+        };
+
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            ensure!(
+                metadata.file_type().is_socket(),
+                "{} already exists and is not a socket",
+                path.display()
+            );
+            ensure!(
+                UnixStream::connect(path).is_err(),
+                "another session is already listening on {}",
+                path.display()
+            );
+            fs::remove_file(path)?;
+        }
+        Ok(SocketListener {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SocketListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::KeyCode;
+
+    use super::*;
+    use crate::output::OutputConfig;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            ScriptCommand::parse("press z").unwrap(),
+            ScriptCommand::Control(ControlCommand::Press(KeyCode::Char('z')))
+        );
+        assert_eq!(
+            ScriptCommand::parse("  record main start ").unwrap(),
+            ScriptCommand::Control(ControlCommand::StartMainRecording)
+        );
+        assert_eq!(
+            ScriptCommand::parse("save main big  take").unwrap(),
+            ScriptCommand::SaveMain {
+                name: "big take".to_string(),
+                overwrite: false
+            }
+        );
+        assert_eq!(
+            ScriptCommand::parse("overwrite main").unwrap(),
+            ScriptCommand::SaveMain {
+                name: String::new(),
+                overwrite: true
+            }
+        );
+        assert!(ScriptCommand::parse("press").is_err());
+        assert!(ScriptCommand::parse("record main").is_err());
+    }
+
+    #[test]
+    fn replies_to_each_line_with_json() {
+        let mut session = ScriptSession::new(Uauauiua::new(OutputConfig::Null, false));
+        let mut output = Vec::new();
+        session
+            .serve(
+                "status\n\nsave main take1\nexplode\n".as_bytes(),
+                &mut output,
+            )
+            .unwrap();
+
+        let replies: Vec<Json> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["ok"], true);
+        assert_eq!(replies[0]["status"]["recording_main"], false);
+        assert_eq!(replies[1]["ok"], false);
+        assert_eq!(replies[1]["error"], "no main recording to save");
+        assert_eq!(replies[2]["ok"], false);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaces_stale_sockets_and_cleans_up() {
+        use std::{env, fs, os::unix::net::UnixListener, process};
+
+        let path = env::temp_dir().join(format!("uauauiua-socket-{}", process::id()));
+        // Dropping a plain listener leaves its socket behind, like a killed session
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = SocketListener::bind(&path).unwrap();
+        assert!(SocketListener::bind(&path).is_err());
+        drop(listener);
+        assert!(!path.exists());
+
+        fs::write(&path, "not a socket").unwrap();
+        assert!(SocketListener::bind(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
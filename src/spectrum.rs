@@ -0,0 +1,219 @@
+use std::{
+    f32::consts::PI,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        mpsc::{Receiver, SyncSender, sync_channel},
+    },
+    thread,
+};
+
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+/// Frames per analysis, which sets the frequency resolution
+pub const SPECTRUM_BLOCK_FRAMES: usize = 2048;
+// Blocks waiting to be analyzed. Past this the mixer drops blocks rather than wait
+const QUEUED_BLOCKS: usize = 4;
+// Enough buffers for a full queue, the block being analyzed and the one being filled
+const BLOCK_BUFFERS: usize = QUEUED_BLOCKS + 2;
+const SILENCE_DB: f32 = -120.0;
+// How fast held peaks fall back down, per block
+const PEAK_FALL_DB: f32 = 1.5;
+// The lowest frequency on a log axis, since 0 Hz can't be on one
+const MIN_LOG_FREQUENCY: f32 = 20.0;
+
+/// The level of each frequency bin of the mixer's output, in decibels relative to full
+/// scale, along with the peaks they've recently reached.
+#[derive(Clone)]
+pub struct Spectrum {
+    sample_rate: u32,
+    levels: Vec<f32>,
+    peaks: Vec<f32>,
+}
+
+impl Spectrum {
+    fn new(sample_rate: u32) -> Self {
+        Spectrum {
+            sample_rate,
+            levels: vec![SILENCE_DB; SPECTRUM_BLOCK_FRAMES / 2],
+            peaks: vec![SILENCE_DB; SPECTRUM_BLOCK_FRAMES / 2],
+        }
+    }
+
+    fn update(&mut self, levels: Vec<f32>) {
+        for (peak, &level) in self.peaks.iter_mut().zip(&levels) {
+            *peak = f32::max(level, *peak - PEAK_FALL_DB);
+        }
+        self.levels = levels;
+    }
+
+    /// Groups the bins into `count` bands spaced evenly in frequency, or in pitch if
+    /// `log_axis` is set, returning the loudest level and peak in each.
+    pub fn bands(&self, count: usize, log_axis: bool) -> Vec<(f32, f32)> {
+        #[allow(clippy::cast_precision_loss)]
+        let (bin_width, nyquist) = (
+            self.sample_rate as f32 / SPECTRUM_BLOCK_FRAMES as f32,
+            self.sample_rate as f32 / 2.0,
+        );
+        #[allow(clippy::cast_precision_loss)]
+        let edge = |i: usize| {
+            let fraction = i as f32 / count as f32;
+            if log_axis {
+                MIN_LOG_FREQUENCY * (nyquist / MIN_LOG_FREQUENCY).powf(fraction)
+            } else {
+                nyquist * fraction
+            }
+        };
+
+        (0..count)
+            .map(|i| {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let start = ((edge(i) / bin_width) as usize).min(self.levels.len() - 1);
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let end =
+                    ((edge(i + 1) / bin_width).ceil() as usize).clamp(start + 1, self.levels.len());
+                let loudest = |x: &[f32]| x.iter().copied().fold(SILENCE_DB, f32::max);
+                (
+                    loudest(&self.levels[start..end]),
+                    loudest(&self.peaks[start..end]),
+                )
+            })
+            .collect()
+    }
+}
+
+// Tapers each block to nothing at the ends, so that it doesn't smear across every bin
+fn hann_window() -> Vec<f32> {
+    #[allow(clippy::cast_precision_loss)]
+    (0..SPECTRUM_BLOCK_FRAMES)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / SPECTRUM_BLOCK_FRAMES as f32).cos())
+        .collect()
+}
+
+/// Works out the level of each bin of a block of mono samples.
+fn analyze(block: &[f32], fft: &dyn Fft<f32>, window: &[f32]) -> Vec<f32> {
+    let mut buffer: Vec<Complex<f32>> = block
+        .iter()
+        .zip(window)
+        .map(|(x, w)| Complex::new(x * w, 0.0))
+        .collect();
+    fft.process(&mut buffer);
+
+    // Scaled so that a full scale sine wave comes out at 0 dB
+    let scale = 2.0 / window.iter().sum::<f32>();
+    buffer[..SPECTRUM_BLOCK_FRAMES / 2]
+        .iter()
+        .map(|c| 20.0 * (c.norm() * scale).max(f32::MIN_POSITIVE).log10())
+        .map(|db| db.max(SILENCE_DB))
+        .collect()
+}
+
+/// Where the mixer sends its blocks, and where it gets them back once they've been
+/// analyzed, so that it can refill them rather than allocate new ones.
+pub struct SpectrumTap {
+    pub blocks: SyncSender<Vec<f32>>,
+    pub recycled: Receiver<Vec<f32>>,
+}
+
+/// Analyzes blocks of the mixer's output on a thread of its own, which ends when the
+/// mixer drops its tap.
+pub struct SpectrumAnalyzer {
+    spectrum: Arc<Mutex<Spectrum>>,
+}
+
+impl SpectrumAnalyzer {
+    /// Starts analyzing, returning where the mixer should send its blocks.
+    pub fn spawn(sample_rate: u32) -> (Self, SpectrumTap) {
+        let (tx, blocks) = sync_channel(QUEUED_BLOCKS);
+        let (recycle_tx, recycled) = sync_channel(BLOCK_BUFFERS);
+        for _ in 0..BLOCK_BUFFERS {
+            let _ = recycle_tx.try_send(Vec::with_capacity(SPECTRUM_BLOCK_FRAMES));
+        }
+        let spectrum = Arc::new(Mutex::new(Spectrum::new(sample_rate)));
+        thread::spawn({
+            let spectrum = spectrum.clone();
+            move || run(&blocks, &recycle_tx, &spectrum)
+        });
+        (
+            SpectrumAnalyzer { spectrum },
+            SpectrumTap {
+                blocks: tx,
+                recycled,
+            },
+        )
+    }
+
+    pub fn spectrum(&self) -> MutexGuard<'_, Spectrum> {
+        self.spectrum
+            .lock()
+            .expect("spectrum should not be poisoned")
+    }
+}
+
+fn run(blocks: &Receiver<Vec<f32>>, recycle_tx: &SyncSender<Vec<f32>>, spectrum: &Mutex<Spectrum>) {
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(SPECTRUM_BLOCK_FRAMES);
+    let window = hann_window();
+    while let Ok(mut block) = blocks.recv() {
+        let levels = analyze(&block, fft.as_ref(), &window);
+        spectrum
+            .lock()
+            .expect("spectrum should not be poisoned")
+            .update(levels);
+        block.clear();
+        // Only fails if the mixer made buffers of its own, in which case this one can go
+        let _ = recycle_tx.try_send(block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn sine(frequency: f32) -> Vec<f32> {
+        #[allow(clippy::cast_precision_loss)]
+        (0..SPECTRUM_BLOCK_FRAMES)
+            .map(|i| (2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    fn spectrum_of(block: &[f32]) -> Spectrum {
+        let fft = FftPlanner::<f32>::new().plan_fft_forward(SPECTRUM_BLOCK_FRAMES);
+        let mut spectrum = Spectrum::new(SAMPLE_RATE);
+        spectrum.update(analyze(block, fft.as_ref(), &hann_window()));
+        spectrum
+    }
+
+    #[test]
+    fn finds_a_sine_wave() {
+        // Exactly on bin 64, so it doesn't leak into its neighbours much
+        let spectrum = spectrum_of(&sine(1500.0));
+        let loudest = (0..spectrum.levels.len())
+            .max_by(|&a, &b| spectrum.levels[a].total_cmp(&spectrum.levels[b]))
+            .unwrap();
+        assert_eq!(loudest, 64);
+        assert!(spectrum.levels[64].abs() < 1.0);
+        assert!(spectrum.levels[200] < -60.0);
+    }
+
+    #[test]
+    fn bands_cover_the_spectrum() {
+        let spectrum = spectrum_of(&sine(1500.0));
+        for log_axis in [false, true] {
+            let bands = spectrum.bands(32, log_axis);
+            assert_eq!(bands.len(), 32);
+            // The window spreads the sine across a few bins, which can straddle bands
+            let loud = bands.iter().filter(|(level, _)| *level > -20.0).count();
+            assert!((1..=3).contains(&loud), "{loud} loud bands");
+        }
+    }
+
+    #[test]
+    fn peaks_fall_slowly() {
+        let mut spectrum = spectrum_of(&sine(1500.0));
+        let level = spectrum.levels[64];
+        spectrum.update(vec![SILENCE_DB; SPECTRUM_BLOCK_FRAMES / 2]);
+        assert_eq!(spectrum.levels[64], SILENCE_DB);
+        assert_eq!(spectrum.peaks[64], level - PEAK_FALL_DB);
+    }
+}
@@ -1,15 +1,33 @@
-use std::mem;
+use std::{
+    collections::HashSet,
+    mem,
+    time::{Duration, Instant},
+};
 
+use anyhow::{anyhow, ensure};
 use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use image::RgbaImage;
 use indexmap::IndexSet;
 use ratatui::{
     DefaultTerminal,
     buffer::Buffer,
-    layout::Rect,
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
     text::{Line, Text},
-    widgets::Widget,
+    widgets::{Block, Clear, LineGauge, Widget},
 };
 
+use crate::control::ControlCommand;
+use crate::disk_recording::Take;
+use crate::export::ExportOptions;
+use crate::keyboard::{KEYBOARD_ROWS, KeyboardView, off_layout};
+#[cfg(feature = "midi")]
+use crate::midi::{NoteOn, VirtualInput};
+use crate::osc::OscServer;
+use crate::output::OutputConfig;
+use crate::palette::fuzzy_filter;
+use crate::preview::{ImagePreview, SpectrumView, Waveform, WaveformPreview, value_to_image};
+use crate::recording::CHANNEL_COUNT;
 use crate::uauauiua::Uauauiua;
 
 const MAIN_RECORD_KEY: KeyCode = KeyCode::Enter;
@@ -20,36 +38,288 @@ const EXIT_KEY: KeyCode = KeyCode::Esc;
 const REINIT_AUDIO_KEY: KeyCode = KeyCode::Home;
 const CLEAR_STACK_KEY: KeyCode = KeyCode::Backspace;
 const CLEAR_RECORDINGS_KEY: KeyCode = KeyCode::Delete;
+const MAIN_PREROLL_KEY: KeyCode = KeyCode::PageUp;
+const SECONDARY_PREROLL_KEY: KeyCode = KeyCode::PageDown;
+const EXPORT_FORMAT_KEY: KeyCode = KeyCode::F(2);
+const NORMALIZE_KEY: KeyCode = KeyCode::F(3);
+const TRIM_SILENCE_KEY: KeyCode = KeyCode::F(4);
+const STEMS_KEY: KeyCode = KeyCode::F(5);
+const REPLAY_KEY: KeyCode = KeyCode::F(6);
+const REPLAY_RECORD_KEY: KeyCode = KeyCode::F(10);
+const SPECTRUM_KEY: KeyCode = KeyCode::F(7);
+const SPECTRUM_AXIS_KEY: KeyCode = KeyCode::F(8);
+const PEAK_HOLD_KEY: KeyCode = KeyCode::F(9);
+const HELP_KEY: KeyCode = KeyCode::Char('?');
+const PALETTE_KEY: KeyCode = KeyCode::F(1);
+const OVERWRITE_KEY: KeyCode = KeyCode::Char('y');
+const AUTO_SUFFIX_KEY: KeyCode = KeyCode::Char('n');
+const LOG_SCROLL_UP_KEY: KeyCode = KeyCode::Up;
+const LOG_SCROLL_DOWN_KEY: KeyCode = KeyCode::Down;
 const HOLD_MODIFIER: KeyModifiers = KeyModifiers::SHIFT;
+// Including the border
+const LOG_PANE_HEIGHT: u16 = 10;
+// In cells, not including the border
+const PREVIEW_WIDTH: u16 = 32;
+const PREVIEW_HEIGHT: u16 = 16;
+const MAX_WAVEFORM_ROWS: u16 = 8;
+const WAVEFORM_LABEL_WIDTH: u16 = 22;
+// Including the border
+const SPECTRUM_PANE_HEIGHT: u16 = 10;
+// How often to check for MIDI notes and OSC messages while waiting for a key press
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(2);
+// How often to redraw when nothing happens, to keep the meters moving
+const REDRAW_INTERVAL: Duration = Duration::from_millis(50);
+// How long a key on the on-screen keyboard lights up after it's triggered
+const KEY_FLASH: Duration = Duration::from_millis(150);
+// How long the clip light stays on after the output clips
+const CLIP_HOLD: Duration = Duration::from_secs(2);
+// The quietest level the meters show
+const METER_FLOOR_DB: f32 = -60.0;
+// In cells, including the border
+const OVERLAY_WIDTH: u16 = 64;
 
 enum Mode {
     Loading,
     Jam,
-    SaveMain(Vec<f32>),
+    SaveMain {
+        take: Take,
+        confirming_overwrite: bool,
+    },
     SaveSecondary(Vec<f32>),
+    Replay {
+        record: bool,
+    },
+    Help,
+    /// Typing into `input` to search for an action, with the `selected`th match picked
+    Palette {
+        selected: usize,
+    },
+}
+
+/// Something a key does in jam mode instead of playing a sound, which can also be run
+/// from the command palette.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    ToggleMainRecording,
+    ToggleSecondaryRecording,
+    SaveMainPreroll,
+    SaveSecondaryPreroll,
+    Reload,
+    StopPlayback,
+    ReinitAudio,
+    ClearStack,
+    ClearRecordings,
+    ToggleStems,
+    Replay,
+    ToggleSpectrum,
+    ToggleSpectrumAxis,
+    ToggleSpectrumPeakHold,
+    ScrollLogUp,
+    ScrollLogDown,
+    Help,
+    CommandPalette,
+    Exit,
+}
+
+impl Action {
+    const ALL: [Action; 19] = [
+        Action::ToggleMainRecording,
+        Action::ToggleSecondaryRecording,
+        Action::SaveMainPreroll,
+        Action::SaveSecondaryPreroll,
+        Action::Reload,
+        Action::StopPlayback,
+        Action::ReinitAudio,
+        Action::ClearStack,
+        Action::ClearRecordings,
+        Action::ToggleStems,
+        Action::Replay,
+        Action::ToggleSpectrum,
+        Action::ToggleSpectrumAxis,
+        Action::ToggleSpectrumPeakHold,
+        Action::ScrollLogUp,
+        Action::ScrollLogDown,
+        Action::Help,
+        Action::CommandPalette,
+        Action::Exit,
+    ];
+
+    fn key(self) -> KeyCode {
+        match self {
+            Action::ToggleMainRecording => MAIN_RECORD_KEY,
+            Action::ToggleSecondaryRecording => SECONDARY_RECORD_KEY,
+            Action::SaveMainPreroll => MAIN_PREROLL_KEY,
+            Action::SaveSecondaryPreroll => SECONDARY_PREROLL_KEY,
+            Action::Reload => RELOAD_KEY,
+            Action::StopPlayback => STOP_PLAYBACK_KEY,
+            Action::ReinitAudio => REINIT_AUDIO_KEY,
+            Action::ClearStack => CLEAR_STACK_KEY,
+            Action::ClearRecordings => CLEAR_RECORDINGS_KEY,
+            Action::ToggleStems => STEMS_KEY,
+            Action::Replay => REPLAY_KEY,
+            Action::ToggleSpectrum => SPECTRUM_KEY,
+            Action::ToggleSpectrumAxis => SPECTRUM_AXIS_KEY,
+            Action::ToggleSpectrumPeakHold => PEAK_HOLD_KEY,
+            Action::ScrollLogUp => LOG_SCROLL_UP_KEY,
+            Action::ScrollLogDown => LOG_SCROLL_DOWN_KEY,
+            Action::Help => HELP_KEY,
+            Action::CommandPalette => PALETTE_KEY,
+            Action::Exit => EXIT_KEY,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Action::ToggleMainRecording => "Start or stop file recording",
+            Action::ToggleSecondaryRecording => "Start or stop Uiua recording",
+            Action::SaveMainPreroll => "Save what you just played to a file",
+            Action::SaveSecondaryPreroll => "Save what you just played to Uiua",
+            Action::Reload => "Reload main.ua",
+            Action::StopPlayback => "Stop playback",
+            Action::ReinitAudio => "Reinitialize audio",
+            Action::ClearStack => "Clear the stack",
+            Action::ClearRecordings => "Clear recordings",
+            Action::ToggleStems => "Toggle stem recording (export each key separately)",
+            Action::Replay => "Replay a recorded performance or MIDI file",
+            Action::ToggleSpectrum => "Show or hide the spectrum",
+            Action::ToggleSpectrumAxis => "Switch the spectrum between log and linear",
+            Action::ToggleSpectrumPeakHold => "Toggle the spectrum's peak hold",
+            Action::ScrollLogUp => "Scroll the output log up",
+            Action::ScrollLogDown => "Scroll the output log down",
+            Action::Help => "Show help",
+            Action::CommandPalette => "Search for a command",
+            Action::Exit => "Exit",
+        }
+    }
+
+    // Keys typed into a prompt are text, so only actions on keys that can't be typed
+    // still work there
+    fn works_in_prompts(self) -> bool {
+        matches!(
+            self,
+            Action::Reload
+                | Action::StopPlayback
+                | Action::ReinitAudio
+                | Action::ClearStack
+                | Action::ClearRecordings
+                | Action::ToggleStems
+                | Action::ToggleSpectrum
+                | Action::ToggleSpectrumAxis
+                | Action::ToggleSpectrumPeakHold
+                | Action::ScrollLogUp
+                | Action::ScrollLogDown
+        )
+    }
+
+    fn from_key(key: KeyCode) -> Option<Action> {
+        Action::ALL.into_iter().find(|a| a.key() == key)
+    }
+}
+
+/// A box in the middle of `area`, as tall as it needs to be if there's room.
+fn centered(area: Rect, height: u16) -> Rect {
+    let width = OVERLAY_WIDTH.min(area.width);
+    let height = height.min(area.height);
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+/// Formats a duration like 1:02.5.
+fn format_duration(duration: Duration) -> String {
+    let tenths = duration.as_millis() / 100;
+    format!("{}:{:02}.{}", tenths / 600, tenths / 10 % 60, tenths % 10)
+}
+
+/// Asks for the line main.ua is waiting on with `&sc`. Returns `None` if the user gives
+/// up, which main.ua sees as the end of input.
+fn read_line(terminal: &mut DefaultTerminal) -> Option<String> {
+    let mut input = String::new();
+    loop {
+        let prompt = Text::raw(format!(
+            "main.ua is asking for a line of input \
+            (press Enter to send it or {EXIT_KEY} to send nothing): {input}_"
+        ));
+        terminal
+            .draw(|f| f.render_widget(prompt, f.area()))
+            .expect("should have drawn terminal");
+
+        let Event::Key(e) = event::read().expect("should have handled terminal event") else {
+            continue;
+        };
+        if e.kind != KeyEventKind::Press {
+            continue;
+        }
+        match e.code {
+            KeyCode::Enter => return Some(input),
+            key if key == EXIT_KEY => return None,
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            _ => {}
+        }
+    }
 }
 
 pub struct Tui {
     uauauiua: Uauauiua,
+    #[cfg(feature = "midi")]
+    midi_input: Option<VirtualInput>,
+    osc_server: Option<OscServer>,
     mode: Mode,
     last_error: Option<anyhow::Error>,
+    last_notice: Option<String>,
     input: String,
+    export_options: ExportOptions,
+    // How many lines the log pane is scrolled back from the newest
+    log_scroll: usize,
+    // Worked out on load rather than on every draw
+    waveforms: Vec<(KeyCode, Waveform)>,
+    stack_images: Vec<Option<RgbaImage>>,
+    last_clip: Option<Instant>,
+    show_spectrum: bool,
+    log_spectrum: bool,
+    spectrum_peak_hold: bool,
     exiting: bool,
 }
 
-impl Default for Tui {
-    fn default() -> Self {
+impl Tui {
+    pub fn new(
+        output_config: OutputConfig,
+        allow_git: bool,
+        osc_server: Option<OscServer>,
+    ) -> Self {
         Self {
-            uauauiua: Uauauiua::default(),
+            uauauiua: Uauauiua::new(output_config, allow_git),
+            #[cfg(feature = "midi")]
+            midi_input: None,
+            osc_server,
             mode: Mode::Jam,
             last_error: None,
+            last_notice: None,
             input: String::new(),
+            export_options: ExportOptions::default(),
+            log_scroll: 0,
+            waveforms: Vec::new(),
+            stack_images: Vec::new(),
+            last_clip: None,
+            show_spectrum: false,
+            log_spectrum: true,
+            spectrum_peak_hold: true,
             exiting: false,
         }
     }
-}
 
-impl Tui {
+    /// Plays notes from a controller as well as the keyboard.
+    #[cfg(feature = "midi")]
+    pub fn with_midi_input(self, midi_input: Option<VirtualInput>) -> Self {
+        Self { midi_input, ..self }
+    }
+
     fn draw(&self, terminal: &mut DefaultTerminal) {
         terminal
             .draw(|f| f.render_widget(self, f.area()))
@@ -63,19 +333,38 @@ impl Tui {
     }
 
     pub fn run(mut self, mut terminal: DefaultTerminal) {
+        self.recover_recordings();
         self.load_uiua(&mut terminal);
 
         'main: loop {
+            if self.uauauiua.meter().take_clipped() {
+                self.last_clip = Some(Instant::now());
+            }
             self.draw(&mut terminal);
+            let next_draw = Instant::now() + REDRAW_INTERVAL;
 
-            self.last_error = None;
-
-            loop {
+            while Instant::now() < next_draw {
+                let played_notes = self.play_midi_notes();
+                let handled_commands = self.handle_control_commands(&mut terminal);
+                if played_notes || handled_commands {
+                    break;
+                }
+                // Without MIDI or OSC to check on, nothing can happen before the next
+                // draw that the terminal wouldn't wake us up for
+                let poll_interval = if self.has_midi_input() || self.osc_server.is_some() {
+                    INPUT_POLL_INTERVAL
+                } else {
+                    next_draw.saturating_duration_since(Instant::now())
+                };
+                if !event::poll(poll_interval).expect("should have polled terminal events") {
+                    continue;
+                }
                 if let Event::Key(e) = event::read().expect("should have handled terminal event") {
                     let key = e.code;
                     let modifiers = e.modifiers;
 
                     if let KeyEventKind::Press = e.kind {
+                        self.clear_messages();
                         let r = self.handle_key_press(key, modifiers, &mut terminal);
 
                         if self.exiting {
@@ -90,11 +379,143 @@ impl Tui {
         }
     }
 
+    /// Forgets the last error and notice, which are shown until something else happens.
+    fn clear_messages(&mut self) {
+        self.last_error = None;
+        self.last_notice = None;
+    }
+
+    #[cfg(feature = "midi")]
+    fn has_midi_input(&self) -> bool {
+        self.midi_input.is_some()
+    }
+
+    #[cfg(not(feature = "midi"))]
+    fn has_midi_input(&self) -> bool {
+        false
+    }
+
+    /// Plays the notes that came in from the MIDI input, returning whether there were any.
+    #[cfg(feature = "midi")]
+    fn play_midi_notes(&mut self) -> bool {
+        let Some(input) = &self.midi_input else {
+            return false;
+        };
+        let notes: Vec<NoteOn> = input.notes().collect();
+        if !notes.is_empty() {
+            self.clear_messages();
+        }
+        for note in &notes {
+            let r = self.uauauiua.play_note(note.key, note.velocity);
+            self.handle_result(r);
+        }
+        !notes.is_empty()
+    }
+
+    #[cfg(not(feature = "midi"))]
+    fn play_midi_notes(&mut self) -> bool {
+        false
+    }
+
+    /// Runs the commands that came in over OSC, returning whether there were any.
+    fn handle_control_commands(&mut self, terminal: &mut DefaultTerminal) -> bool {
+        let Some(server) = &self.osc_server else {
+            return false;
+        };
+        let commands: Vec<_> = server.commands().collect();
+        if !commands.is_empty() {
+            self.clear_messages();
+        }
+        for command in &commands {
+            let r = match command {
+                Ok(c) => self.handle_control_command(*c, terminal),
+                Err(e) => Err(anyhow!("OSC: {e}")),
+            };
+            self.handle_result(r);
+        }
+        !commands.is_empty()
+    }
+
+    fn handle_control_command(
+        &mut self,
+        command: ControlCommand,
+        terminal: &mut DefaultTerminal,
+    ) -> anyhow::Result<()> {
+        match command {
+            ControlCommand::Press(key) => self.uauauiua.add_to_mixer(key, false)?,
+            ControlCommand::ToggleHold(key) => self.uauauiua.add_to_mixer(key, true)?,
+            ControlCommand::StopPlayback => self.uauauiua.stop_playback()?,
+            ControlCommand::StartMainRecording => {
+                ensure!(
+                    !self.uauauiua.is_recording_main(),
+                    "already recording to a file"
+                );
+                self.uauauiua.start_main_recording()?;
+            }
+            ControlCommand::StartSecondaryRecording => {
+                ensure!(
+                    !self.uauauiua.is_recording_secondary(),
+                    "already recording to Uiua"
+                );
+                self.uauauiua.start_secondary_recording()?;
+            }
+            // Stopping a recording asks for its name, which can't replace another prompt
+            ControlCommand::StopMainRecording => {
+                ensure!(
+                    matches!(self.mode, Mode::Jam),
+                    "finish the current prompt first"
+                );
+                ensure!(self.uauauiua.is_recording_main(), "not recording to a file");
+                self.mode = Mode::SaveMain {
+                    take: self.uauauiua.stop_main_recording()?,
+                    confirming_overwrite: false,
+                };
+            }
+            ControlCommand::StopSecondaryRecording => {
+                ensure!(
+                    matches!(self.mode, Mode::Jam),
+                    "finish the current prompt first"
+                );
+                ensure!(
+                    self.uauauiua.is_recording_secondary(),
+                    "not recording to Uiua"
+                );
+                self.mode = Mode::SaveSecondary(self.uauauiua.stop_secondary_recording()?);
+            }
+            ControlCommand::Reload => self.load_uiua(terminal),
+        }
+        Ok(())
+    }
+
+    fn recover_recordings(&mut self) {
+        match self.uauauiua.recover_recordings() {
+            Ok(paths) if !paths.is_empty() => {
+                self.last_notice = Some(format!(
+                    "Recovered unsaved recordings: {}",
+                    paths
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+            r => self.handle_result(r),
+        }
+    }
+
     fn load_uiua(&mut self, terminal: &mut DefaultTerminal) {
         let current_mode = mem::replace(&mut self.mode, Mode::Loading);
         self.draw(terminal);
-        let r = self.uauauiua.load();
+        let r = self.uauauiua.load_with_input(|| read_line(terminal));
         self.handle_result(r);
+        self.log_scroll = 0;
+        self.waveforms = self
+            .uauauiua
+            .key_sources()
+            .iter()
+            .map(|(k, s)| (*k, Waveform::new(s)))
+            .collect();
+        self.stack_images = self.uauauiua.stack().iter().map(value_to_image).collect();
         self.mode = current_mode;
     }
 
@@ -105,16 +526,115 @@ impl Tui {
         terminal: &mut DefaultTerminal,
     ) -> anyhow::Result<()> {
         match (&mut self.mode, key) {
-            (Mode::SaveMain(_) | Mode::SaveSecondary(_), key) if key == EXIT_KEY => {
+            (Mode::Help, _) => {
+                self.mode = Mode::Jam;
+            }
+            (Mode::Palette { .. }, key) if key == EXIT_KEY => {
+                self.input.clear();
+                self.mode = Mode::Jam;
+            }
+            (Mode::Palette { selected }, KeyCode::Up) => {
+                *selected = selected.saturating_sub(1);
+            }
+            (Mode::Palette { selected }, KeyCode::Down) => {
+                let selected = *selected + 1;
+                self.mode = Mode::Palette {
+                    selected: selected.min(self.palette_matches().len().saturating_sub(1)),
+                };
+            }
+            (Mode::Palette { selected }, KeyCode::Enter) => {
+                let selected = *selected;
+                let action = self.palette_matches().get(selected).copied();
+                self.input.clear();
+                self.mode = Mode::Jam;
+                if let Some(action) = action {
+                    self.run_action(action, terminal)?;
+                }
+            }
+            (Mode::Palette { selected }, KeyCode::Char(c)) => {
+                self.input.push(c);
+                *selected = 0;
+            }
+            (Mode::Palette { selected }, KeyCode::Backspace) => {
+                self.input.pop();
+                *selected = 0;
+            }
+            (Mode::Palette { .. }, _) => {}
+            (
+                Mode::SaveMain {
+                    take,
+                    confirming_overwrite: true,
+                },
+                key,
+            ) if key == OVERWRITE_KEY || key == AUTO_SUFFIX_KEY => {
+                let name = if key == AUTO_SUFFIX_KEY {
+                    self.uauauiua
+                        .next_free_main_recording_name(&self.input, self.export_options)
+                } else {
+                    self.input.clone()
+                };
+                self.uauauiua
+                    .save_main_recording(take, &name, self.export_options)?;
+                self.input.clear();
+                self.mode = Mode::Jam;
+            }
+            (
+                Mode::SaveMain {
+                    confirming_overwrite: confirming_overwrite @ true,
+                    ..
+                },
+                key,
+            ) if key == EXIT_KEY => {
+                *confirming_overwrite = false;
+            }
+            (
+                Mode::SaveMain {
+                    confirming_overwrite: true,
+                    ..
+                },
+                _,
+            ) => {}
+            (Mode::SaveMain { take, .. }, key) if key == EXIT_KEY => {
+                // Leave the prompt even if cleaning up failed, or Esc would keep failing
+                let discarded = self.uauauiua.discard_main_recording(take);
+                self.mode = Mode::Jam;
+                discarded?;
+            }
+            (Mode::SaveSecondary(_), key) if key == EXIT_KEY => {
+                self.mode = Mode::Jam;
+            }
+            (Mode::Replay { .. }, key) if key == EXIT_KEY => {
+                self.input.clear();
+                self.mode = Mode::Jam;
+            }
+            (Mode::Replay { record }, KeyCode::Enter) => {
+                let path = self.uauauiua.replay_path(&self.input)?;
+                self.uauauiua.replay(&path, *record)?;
+                self.input.clear();
                 self.mode = Mode::Jam;
             }
-            (Mode::SaveMain(v), KeyCode::Enter) => {
-                if self.input.is_empty() {
+            (Mode::Replay { record }, key) if key == REPLAY_RECORD_KEY => {
+                *record = !*record;
+            }
+            (
+                Mode::SaveMain {
+                    take,
+                    confirming_overwrite,
+                },
+                KeyCode::Enter,
+            ) => {
+                let name = self.uauauiua.resolve_main_recording_name(&self.input)?;
+                if self
+                    .uauauiua
+                    .main_recording_exists(&name, self.export_options)
+                {
+                    self.input = name;
+                    *confirming_overwrite = true;
                     return Ok(());
                 }
-                let input = mem::take(&mut self.input);
-                let recording = mem::take(v);
-                self.uauauiua.save_main_recording(&recording, &input)?;
+                self.uauauiua
+                    .save_main_recording(take, &name, self.export_options)?;
+                self.input.clear();
                 self.mode = Mode::Jam;
             }
             (Mode::SaveSecondary(v), KeyCode::Enter) => {
@@ -123,51 +643,102 @@ impl Tui {
                 self.uauauiua.save_secondary_recording(&recording, &input);
                 self.mode = Mode::Jam;
             }
-            (Mode::SaveMain(_) | Mode::SaveSecondary(_), KeyCode::Char(c)) => {
-                self.input.push(c);
+            (Mode::SaveMain { .. }, key) if key == EXPORT_FORMAT_KEY => {
+                self.export_options.format = self.export_options.format.next();
             }
-            (Mode::SaveMain(_) | Mode::SaveSecondary(_), KeyCode::Backspace) => {
-                self.input.pop();
+            (Mode::SaveMain { .. }, key) if key == NORMALIZE_KEY => {
+                self.export_options.normalize = !self.export_options.normalize;
             }
-            (_, key) if key == RELOAD_KEY => {
-                self.load_uiua(terminal);
+            (Mode::SaveMain { .. }, key) if key == TRIM_SILENCE_KEY => {
+                self.export_options.trim_silence = !self.export_options.trim_silence;
             }
-            (_, key) if key == REINIT_AUDIO_KEY => {
-                self.uauauiua.reinit_audio();
+            (
+                Mode::SaveMain { .. } | Mode::SaveSecondary(_) | Mode::Replay { .. },
+                KeyCode::Char(c),
+            ) => {
+                self.input.push(c);
             }
-            (_, key) if key == CLEAR_STACK_KEY => {
-                self.uauauiua.clear_stack();
+            (
+                Mode::SaveMain { .. } | Mode::SaveSecondary(_) | Mode::Replay { .. },
+                KeyCode::Backspace,
+            ) => {
+                self.input.pop();
             }
-            (_, key) if key == CLEAR_RECORDINGS_KEY => {
-                self.uauauiua.clear_recordings();
+            (mode, key) => {
+                let is_prompt = !matches!(mode, Mode::Jam);
+                match Action::from_key(key).filter(|a| !is_prompt || a.works_in_prompts()) {
+                    Some(action) => self.run_action(action, terminal)?,
+                    None => self
+                        .uauauiua
+                        .add_to_mixer(key, modifiers.contains(HOLD_MODIFIER))?,
+                }
             }
-            (Mode::Jam, key) if key == MAIN_RECORD_KEY && self.uauauiua.is_recording_main() => {
-                self.mode = Mode::SaveMain(self.uauauiua.stop_main_recording()?);
+        }
+        Ok(())
+    }
+
+    /// Does what an action's key does in jam mode.
+    fn run_action(&mut self, action: Action, terminal: &mut DefaultTerminal) -> anyhow::Result<()> {
+        match action {
+            Action::ToggleMainRecording if self.uauauiua.is_recording_main() => {
+                self.mode = Mode::SaveMain {
+                    take: self.uauauiua.stop_main_recording()?,
+                    confirming_overwrite: false,
+                };
             }
-            (Mode::Jam, key)
-                if key == SECONDARY_RECORD_KEY && self.uauauiua.is_recording_secondary() =>
-            {
+            Action::ToggleMainRecording => self.uauauiua.start_main_recording()?,
+            Action::ToggleSecondaryRecording if self.uauauiua.is_recording_secondary() => {
                 self.mode = Mode::SaveSecondary(self.uauauiua.stop_secondary_recording()?);
             }
-            (_, key) if key == MAIN_RECORD_KEY => {
-                self.uauauiua.start_main_recording()?;
+            Action::ToggleSecondaryRecording => self.uauauiua.start_secondary_recording()?,
+            Action::SaveMainPreroll => {
+                self.mode = Mode::SaveMain {
+                    take: self.uauauiua.capture_main_preroll()?,
+                    confirming_overwrite: false,
+                };
             }
-            (_, key) if key == SECONDARY_RECORD_KEY => {
-                self.uauauiua.start_secondary_recording()?;
+            Action::SaveSecondaryPreroll => {
+                self.mode = Mode::SaveSecondary(self.uauauiua.capture_preroll()?);
             }
-            (_, key) if key == EXIT_KEY => {
-                self.exiting = true;
+            Action::Reload => self.load_uiua(terminal),
+            Action::StopPlayback => self.uauauiua.stop_playback()?,
+            Action::ReinitAudio => self.uauauiua.reinit_audio(),
+            Action::ClearStack => {
+                self.uauauiua.clear_stack();
+                self.stack_images.clear();
             }
-            (_, key) if key == STOP_PLAYBACK_KEY => {
-                self.uauauiua.stop_playback()?;
+            Action::ClearRecordings => self.uauauiua.clear_recordings(),
+            Action::ToggleStems => self.uauauiua.toggle_stem_recording(),
+            Action::Replay => self.mode = Mode::Replay { record: false },
+            Action::ToggleSpectrum => {
+                self.show_spectrum = !self.show_spectrum;
+                self.uauauiua.show_spectrum(self.show_spectrum);
             }
-            (_, _) => {
-                self.uauauiua
-                    .add_to_mixer(key, modifiers.contains(HOLD_MODIFIER))?;
+            Action::ToggleSpectrumAxis => self.log_spectrum = !self.log_spectrum,
+            Action::ToggleSpectrumPeakHold => {
+                self.spectrum_peak_hold = !self.spectrum_peak_hold;
+            }
+            Action::ScrollLogUp => {
+                let line_count = self.uauauiua.uiua_log().lines().len();
+                self.log_scroll = (self.log_scroll + 1).min(line_count.saturating_sub(1));
+            }
+            Action::ScrollLogDown => self.log_scroll = self.log_scroll.saturating_sub(1),
+            Action::Help => self.mode = Mode::Help,
+            Action::CommandPalette => {
+                self.input.clear();
+                self.mode = Mode::Palette { selected: 0 };
             }
+            Action::Exit => self.exiting = true,
         }
         Ok(())
     }
+
+    /// The actions matching what's been typed into the command palette, best first.
+    fn palette_matches(&self) -> Vec<Action> {
+        fuzzy_filter(&Action::ALL, &self.input, |a| {
+            format!("{} {}", a.description(), a.key())
+        })
+    }
 }
 
 impl Widget for &Tui {
@@ -185,19 +756,16 @@ impl Widget for &Tui {
         ));
         let mut t = match self.mode {
             Mode::Loading => Text::raw("Loading..."),
-            Mode::Jam => {
+            Mode::Jam | Mode::Help | Mode::Palette { .. } => {
                 let main = self.uauauiua.is_recording_main();
                 let secondary = self.uauauiua.is_recording_secondary();
 
                 if !main && !secondary {
                     Text::raw(format!(
                         "Press {MAIN_RECORD_KEY} to start file recording, \
-                        {SECONDARY_RECORD_KEY} to start Uiua recording,\n\
-                        {RELOAD_KEY} to reload the file, \
-                        {STOP_PLAYBACK_KEY} to stop playback, \
-                        {REINIT_AUDIO_KEY} to reinitialize audio,\n\
-                        {CLEAR_STACK_KEY} to clear the stack, \
-                        {CLEAR_RECORDINGS_KEY} to clear recordings, \
+                        {SECONDARY_RECORD_KEY} to start Uiua recording, \
+                        {HELP_KEY} to see every control, \
+                        {PALETTE_KEY} to search for a command\n\
                         or {EXIT_KEY} to exit\n\n"
                     ))
                 } else if main && secondary {
@@ -208,32 +776,105 @@ impl Widget for &Tui {
                     secondary_text
                 }
             }
-            Mode::SaveMain(_) => Text::raw(format!(
-                "Enter name (press {EXIT_KEY} to discard): {}_",
-                self.input
+            Mode::SaveMain {
+                confirming_overwrite: true,
+                ..
+            } => Text::raw(format!(
+                "{}.{} already exists. Press {OVERWRITE_KEY} to overwrite it, \
+                {AUTO_SUFFIX_KEY} to save under a new name \
+                or {EXIT_KEY} to pick another name",
+                self.input,
+                self.export_options.format.extension()
             )),
+            Mode::SaveMain { .. } => {
+                fn on_off(x: bool) -> &'static str {
+                    if x { "on" } else { "off" }
+                }
+                let options = self.export_options;
+                Text::raw(format!(
+                    "{EXPORT_FORMAT_KEY}: format ({}), \
+                    {NORMALIZE_KEY}: normalize ({}), \
+                    {TRIM_SILENCE_KEY}: trim silence ({})\n\
+                    Enter name (press {EXIT_KEY} to discard, leave blank for timestamp): {}_",
+                    options.format,
+                    on_off(options.normalize),
+                    on_off(options.trim_silence),
+                    self.input
+                ))
+            }
             Mode::SaveSecondary(_) => Text::raw(format!(
                 "Enter name (press {EXIT_KEY} to discard, leave blank for timestamp): {}_",
                 self.input
             )),
+            Mode::Replay { record } => Text::raw(format!(
+                "{REPLAY_RECORD_KEY}: record the replay ({})\n\
+                Enter the name of a file recording or the path to a MIDI file to replay \
+                (press {EXIT_KEY} to cancel): {}_",
+                if record { "on" } else { "off" },
+                self.input
+            )),
         };
 
+        let defined_sources = self.uauauiua.defined_sources();
+        let other_keys = off_layout(&defined_sources);
+        if !other_keys.is_empty() {
+            t += Line::raw(format!("Other keys: [{}]", join_set(&other_keys)));
+        }
+        let status = self.uauauiua.playback_status();
+        if !self.uauauiua.held_sources().is_empty() {
+            t += Line::raw(format!(
+                "Held: {}",
+                self.uauauiua
+                    .held_sources()
+                    .iter()
+                    .map(|k| {
+                        // Just held keys won't have been published yet
+                        match status.held_positions.iter().find(|(h, _)| h == k) {
+                            Some((_, position)) => format!("{k} {:.0}%", position * 100.0),
+                            None => k.to_string(),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ));
+        }
+        t += Line::raw(format!("Voices playing: {}", status.voices));
+        if let Some((duration, size)) = self.uauauiua.main_recording_progress() {
+            #[allow(clippy::cast_precision_loss)]
+            let megabytes = size as f64 / 1e6;
+            t += Line::raw(format!(
+                "File recording: {} ({megabytes:.1} MB)",
+                format_duration(duration)
+            ));
+        }
+        if let Some(duration) = self.uauauiua.secondary_recording_duration() {
+            t += Line::raw(format!("Uiua recording: {}", format_duration(duration)));
+        }
         t += Line::raw(format!(
-            "Defined sources: [{}]",
-            join_set(&self.uauauiua.defined_sources())
-        ));
-        t += Line::raw(format!(
-            "Held sources: [{}]",
-            join_set(self.uauauiua.held_sources())
+            "Stem recording: {}",
+            if self.uauauiua.is_recording_stems() {
+                "on"
+            } else {
+                "off"
+            }
         ));
         t += Line::raw(format!(
             "Recordings: [{}]",
             join_set(&self.uauauiua.secondary_recording_names())
         ));
 
+        if let Some(server) = &self.osc_server {
+            t += Line::raw(format!("Listening for OSC on {}", server.local_addr()));
+        }
+        if let Some(w) = self.uauauiua.audio_warning() {
+            t += Line::raw(format!("Warning: {w}"));
+        }
         if let Some(e) = &self.last_error {
             t += Line::raw(format!("Error: {e}"));
         }
+        if let Some(n) = &self.last_notice {
+            t += Line::raw(n.clone());
+        }
 
         let stack = self.uauauiua.stack();
         if stack.is_empty() {
@@ -243,12 +884,305 @@ impl Widget for &Tui {
                 "Stack:\n{}",
                 stack
                     .iter()
-                    .map(ToString::to_string)
+                    .zip(&self.stack_images)
+                    .map(|(v, image)| match image {
+                        Some(_) => format!("[image {}]", v.shape()),
+                        None => v.to_string(),
+                    })
                     .collect::<Vec<_>>()
                     .join("\n")
             ));
         }
 
-        t.render(area, buf);
+        let shown_images = self.uauauiua.shown_images();
+        let previews: Vec<(String, &RgbaImage)> = shown_images
+            .iter()
+            .map(|s| {
+                (
+                    s.label.clone().unwrap_or("Shown image".to_string()),
+                    &s.image,
+                )
+            })
+            .chain(
+                self.stack_images
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, image)| {
+                        Some((format!("Stack item {}", i + 1), image.as_ref()?))
+                    }),
+            )
+            .collect();
+        let area = if previews.is_empty() {
+            area
+        } else {
+            let [area, mut preview_area] =
+                Layout::horizontal([Constraint::Min(0), Constraint::Length(PREVIEW_WIDTH + 2)])
+                    .areas(area);
+            for (title, image) in previews {
+                let [preview, rest] =
+                    Layout::vertical([Constraint::Length(PREVIEW_HEIGHT + 2), Constraint::Min(0)])
+                        .areas(preview_area);
+                let block = Block::bordered().title(title);
+                let inner = block.inner(preview);
+                block.render(preview, buf);
+                ImagePreview(image).render(inner, buf);
+                preview_area = rest;
+            }
+            area
+        };
+
+        let log = self.uauauiua.uiua_log();
+        let sounds_height = if self.waveforms.is_empty() {
+            0
+        } else {
+            u16::try_from(self.waveforms.len())
+                .unwrap_or(u16::MAX)
+                .min(MAX_WAVEFORM_ROWS)
+                + 2
+        };
+        let log_height = if log.lines().is_empty() {
+            0
+        } else {
+            LOG_PANE_HEIGHT
+        };
+        let spectrum_height = if self.show_spectrum {
+            SPECTRUM_PANE_HEIGHT
+        } else {
+            0
+        };
+        let [
+            text_area,
+            keyboard_area,
+            meter_area,
+            spectrum_area,
+            sounds_area,
+            log_area,
+        ] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(KEYBOARD_ROWS + 2),
+            Constraint::Length(CHANNEL_COUNT),
+            Constraint::Length(spectrum_height),
+            Constraint::Length(sounds_height),
+            Constraint::Length(log_height),
+        ])
+        .areas(area);
+        t.render(text_area, buf);
+
+        let block = Block::bordered()
+            .title("Keys")
+            .title(KeyboardView::legend().right_aligned());
+        let inner = block.inner(keyboard_area);
+        block.render(keyboard_area, buf);
+        let flashing: HashSet<KeyCode> = self
+            .uauauiua
+            .last_triggered()
+            .iter()
+            .filter(|(_, t)| t.elapsed() < KEY_FLASH)
+            .map(|(k, _)| *k)
+            .collect();
+        KeyboardView {
+            mapped: &defined_sources,
+            held: &self.uauauiua.held_sources(),
+            reserved: &Action::ALL.map(Action::key),
+            flashing: &flashing,
+        }
+        .render(inner, buf);
+
+        let [gauge_area, clip_area] =
+            Layout::horizontal([Constraint::Min(0), Constraint::Length(6)]).areas(meter_area);
+        let to_db = |x: f32| 20.0 * x.max(f32::MIN_POSITIVE).log10();
+        for ((name, (peak, rms)), y) in ["L", "R"]
+            .into_iter()
+            .zip(self.uauauiua.meter().take_levels())
+            .zip(gauge_area.top()..gauge_area.bottom())
+        {
+            let (peak_db, rms_db) = (to_db(peak), to_db(rms));
+            LineGauge::default()
+                .filled_style(if peak > 1.0 {
+                    Style::new().red()
+                } else {
+                    Style::new().green()
+                })
+                .ratio(f64::from((1.0 - peak_db / METER_FLOOR_DB).clamp(0.0, 1.0)))
+                .label(format!(
+                    "{name} {:>5.1} dB (RMS {:>5.1})",
+                    peak_db.max(METER_FLOOR_DB),
+                    rms_db.max(METER_FLOOR_DB)
+                ))
+                .render(
+                    Rect {
+                        y,
+                        height: 1,
+                        ..gauge_area
+                    },
+                    buf,
+                );
+        }
+        if self.last_clip.is_some_and(|t| t.elapsed() < CLIP_HOLD) {
+            Line::raw(" CLIP").red().bold().render(clip_area, buf);
+        }
+
+        if let Some(spectrum) = self.uauauiua.spectrum() {
+            let block = Block::bordered().title(format!(
+                "Spectrum ({} frequency, peak hold {})",
+                if self.log_spectrum { "log" } else { "linear" },
+                if self.spectrum_peak_hold { "on" } else { "off" }
+            ));
+            let inner = block.inner(spectrum_area);
+            block.render(spectrum_area, buf);
+            SpectrumView {
+                spectrum: &spectrum,
+                log_axis: self.log_spectrum,
+                peak_hold: self.spectrum_peak_hold,
+            }
+            .render(inner, buf);
+        }
+
+        if !self.waveforms.is_empty() {
+            // Rows past the limit, or past what fits on a small terminal, are left out
+            let rows = usize::from(sounds_area.height.saturating_sub(2));
+            let hidden = self.waveforms.len().saturating_sub(rows);
+            let block = Block::bordered().title(if hidden == 0 {
+                "Sounds".to_string()
+            } else {
+                format!("Sounds (+{hidden} more)")
+            });
+            let inner = block.inner(sounds_area);
+            block.render(sounds_area, buf);
+            for ((key, waveform), y) in self.waveforms.iter().zip(inner.top()..inner.bottom()) {
+                let row = Rect {
+                    y,
+                    height: 1,
+                    ..inner
+                };
+                let [label_area, waveform_area] = Layout::horizontal([
+                    Constraint::Length(WAVEFORM_LABEL_WIDTH),
+                    Constraint::Min(0),
+                ])
+                .areas(row);
+                let peak = waveform
+                    .peak_db()
+                    .map_or("silent".to_string(), |db| format!("{db:.1} dB"));
+                Line::raw(format!(
+                    "{key} {:.2}s {peak}",
+                    waveform.duration.as_secs_f32()
+                ))
+                .render(label_area, buf);
+                WaveformPreview(waveform).render(waveform_area, buf);
+            }
+        }
+
+        if !log.lines().is_empty() {
+            let block = Block::bordered().title(format!(
+                "Output ({LOG_SCROLL_UP_KEY}/{LOG_SCROLL_DOWN_KEY} to scroll)"
+            ));
+            let inner = block.inner(log_area);
+            block.render(log_area, buf);
+
+            let end = log.lines().len() - self.log_scroll.min(log.lines().len());
+            let start = end.saturating_sub(usize::from(inner.height));
+            let lines: Vec<Line> = log
+                .lines()
+                .range(start..end)
+                .map(|l| {
+                    let line = Line::raw(l.text.as_str());
+                    if l.is_stderr { line.red() } else { line }
+                })
+                .collect();
+            Text::from(lines).render(inner, buf);
+        }
+
+        match self.mode {
+            Mode::Help => self.render_help(area, buf),
+            Mode::Palette { selected } => self.render_palette(selected, area, buf),
+            _ => {}
+        }
+    }
+}
+
+impl Tui {
+    fn render_help(&self, area: Rect, buf: &mut Buffer) {
+        // Keys that only do something in a prompt, listed under the prompt
+        let prompts = [
+            (
+                "When saving a recording",
+                vec![
+                    (EXPORT_FORMAT_KEY, "Change the format"),
+                    (NORMALIZE_KEY, "Toggle normalizing"),
+                    (TRIM_SILENCE_KEY, "Toggle trimming silence"),
+                    (OVERWRITE_KEY, "Overwrite a file with the same name"),
+                    (AUTO_SUFFIX_KEY, "Save under a new name instead"),
+                ],
+            ),
+            (
+                "When replaying a recording",
+                vec![(REPLAY_RECORD_KEY, "Toggle recording the replay")],
+            ),
+        ];
+        let bindings = Action::ALL
+            .iter()
+            .map(|a| (a.key(), a.description()))
+            .chain(prompts.iter().flat_map(|(_, b)| b.iter().copied()));
+        let key_width = bindings
+            .map(|(key, _)| key.to_string().chars().count())
+            .max()
+            .unwrap_or_default();
+        let binding = |(key, description): (KeyCode, &'static str)| {
+            Line::from(vec![
+                format!("{:>key_width$}  ", key.to_string()).bold(),
+                description.into(),
+            ])
+        };
+
+        let mut lines: Vec<Line> = Action::ALL
+            .iter()
+            .map(|a| binding((a.key(), a.description())))
+            .collect();
+        for (title, bindings) in prompts {
+            lines.push(Line::raw(""));
+            lines.push(Line::raw(title).underlined());
+            lines.extend(bindings.into_iter().map(binding));
+        }
+        lines.push(Line::raw(""));
+        lines.push(Line::raw(format!(
+            "Any other key plays its sound, hold {HOLD_MODIFIER} to loop it"
+        )));
+        if self.has_midi_input() {
+            lines.push(Line::raw("MIDI notes play the keys OnNote gives them"));
+        }
+
+        let height = u16::try_from(lines.len())
+            .unwrap_or(u16::MAX)
+            .saturating_add(2);
+        let popup = centered(area, height);
+        Clear.render(popup, buf);
+        let block = Block::bordered().title("Help (press any key to close)");
+        let inner = block.inner(popup);
+        block.render(popup, buf);
+        Text::from(lines).render(inner, buf);
+    }
+
+    fn render_palette(&self, selected: usize, area: Rect, buf: &mut Buffer) {
+        let matches = self.palette_matches();
+        let mut lines = vec![Line::raw(format!("> {}_", self.input))];
+        lines.extend(matches.iter().enumerate().map(|(i, a)| {
+            let line = Line::raw(format!("{} ({})", a.description(), a.key()));
+            if i == selected { line.reversed() } else { line }
+        }));
+        if matches.is_empty() {
+            lines.push(Line::raw("No matching commands").dark_gray());
+        }
+
+        let height = u16::try_from(lines.len())
+            .unwrap_or(u16::MAX)
+            .saturating_add(2);
+        let popup = centered(area, height);
+        Clear.render(popup, buf);
+        let block = Block::bordered().title(format!(
+            "Commands (Up/Down to pick, Enter to run, {EXIT_KEY} to cancel)"
+        ));
+        let inner = block.inner(popup);
+        block.render(popup, buf);
+        Text::from(lines).render(inner, buf);
     }
 }
@@ -1,40 +1,88 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{fs, mem};
-
-use crate::recording::{CHANNEL_COUNT, MixerController, SAMPLE_RATE, new_mixer};
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{MutexGuard, mpsc::channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::disk_recording::{
+    DiskRecording, Take, events_path, next_free_recording_name, recording_exists,
+    recover_orphaned_recordings, resolve_recording_name, timestamp,
+};
+use crate::export::ExportOptions;
+use crate::limited_backend::{OutputLog, ShownImage};
+#[cfg(feature = "midi")]
+use crate::midi::velocity_gain;
+use crate::midi::{is_midi_file, read_notes, schedule_notes};
+use crate::output::{Output, OutputConfig};
+use crate::performance::{read_events, schedule_events};
+use crate::recording::{
+    CHANNEL_COUNT, MainRecordingTx, Meter, MixerController, PlaybackStatus, SAMPLE_RATE,
+    duration_to_samples, frames_to_duration, new_mixer,
+};
+use crate::spectrum::{Spectrum, SpectrumAnalyzer};
 use crate::uiua_extension::UiuaExtension;
 
 use anyhow::{anyhow, ensure};
 use crossterm::event::KeyCode;
-use hound::{SampleFormat, WavSpec, WavWriter};
-use indexmap::IndexSet;
-use rodio::{OutputStream, OutputStreamHandle, Sink, Source};
+use indexmap::{IndexMap, IndexSet};
+use rodio::{Source, buffer::SamplesBuffer};
 use uiua::{Array, Value};
 
-const RECORDINGS_DIR: &str = "recordings";
+const INPUT_REQUEST_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn preroll_length(extension: &UiuaExtension) -> usize {
+    duration_to_samples(extension.preroll_duration())
+        .expect("pre-roll duration should have been limited when main.ua was loaded")
+}
 
 struct AudioHandler {
     mixer_controller: MixerController,
-    _stream: OutputStream,
-    _stream_handle: OutputStreamHandle,
-    _sink: Sink,
+    output: Option<Output>,
+    warning: Option<String>,
+    /// Only running while the spectrum is shown
+    spectrum_analyzer: Option<SpectrumAnalyzer>,
 }
 impl AudioHandler {
-    fn new(is_recording_main: bool, is_recording_secondary: bool) -> Self {
-        let (stream, stream_handle) =
-            OutputStream::try_default().expect("should have initialized audio output stream");
-        let (mixer_controller, mixer) = new_mixer(is_recording_main, is_recording_secondary);
-        let sink = Sink::try_new(&stream_handle).expect("should have initialized audio sink");
-        sink.append(mixer);
+    fn new(
+        output_config: &OutputConfig,
+        append: bool,
+        main_recording_tx: Option<MainRecordingTx>,
+        is_recording_secondary: bool,
+        preroll_length: usize,
+    ) -> Self {
+        let (mixer_controller, mixer) =
+            new_mixer(main_recording_tx, is_recording_secondary, preroll_length);
+        let (output, warning) = Output::open(output_config, append, mixer);
 
         Self {
             mixer_controller,
-            _stream: stream,
-            _stream_handle: stream_handle,
-            _sink: sink,
+            output: Some(output),
+            warning,
+            spectrum_analyzer: None,
         }
     }
 
+    fn analyze_spectrum(&mut self, analyze: bool) {
+        if analyze == self.spectrum_analyzer.is_some() {
+            return;
+        }
+        if analyze {
+            let (spectrum_analyzer, tap) = SpectrumAnalyzer::spawn(*SAMPLE_RATE);
+            // If the mixer's gone, so is the tap, and with it the analyzer's thread
+            let _ = self.mixer_controller.set_tap(tap);
+            self.spectrum_analyzer = Some(spectrum_analyzer);
+        } else {
+            let _ = self.mixer_controller.remove_tap();
+            self.spectrum_analyzer = None;
+        }
+    }
+
+    /// Stops playback, letting go of the sound device or output file.
+    fn close(&mut self) {
+        self.output = None;
+    }
+
     fn mixer_controller(&self) -> &MixerController {
         &self.mixer_controller
     }
@@ -45,25 +93,68 @@ impl AudioHandler {
 
 pub struct Uauauiua {
     uiua_extension: UiuaExtension,
-    partial_main_recording: Vec<f32>,
+    output_config: OutputConfig,
+    main_recording: Option<DiskRecording>,
+    record_stems: bool,
     partial_secondary_recording: Vec<f32>,
     audio_handler: AudioHandler,
+    last_triggered: HashMap<KeyCode, Instant>,
 }
 
-impl Default for Uauauiua {
-    fn default() -> Self {
+impl Uauauiua {
+    pub fn new(output_config: OutputConfig, allow_git: bool) -> Self {
+        let uiua_extension = UiuaExtension::new(allow_git);
+        let preroll_length = preroll_length(&uiua_extension);
+        let audio_handler = AudioHandler::new(&output_config, false, None, false, preroll_length);
+        uiua_extension.connect_mixer(audio_handler.mixer_controller());
         Uauauiua {
-            uiua_extension: UiuaExtension::default(),
-            partial_main_recording: Vec::default(),
+            uiua_extension,
+            output_config,
+            main_recording: None,
+            record_stems: false,
             partial_secondary_recording: Vec::default(),
-            audio_handler: AudioHandler::new(false, false),
+            audio_handler,
+            last_triggered: HashMap::default(),
         }
     }
-}
 
-impl Uauauiua {
     pub fn load(&mut self) -> anyhow::Result<()> {
-        self.uiua_extension.load()
+        self.uiua_extension.load()?;
+        self.apply_preroll_length()
+    }
+
+    /// Loads main.ua on another thread, calling `read_line` on this one whenever main.ua
+    /// asks for a line with `&sc`. `None` is the end of input.
+    pub fn load_with_input(
+        &mut self,
+        mut read_line: impl FnMut() -> Option<String>,
+    ) -> anyhow::Result<()> {
+        let (tx, requests) = channel();
+        self.uiua_extension.connect_input(tx);
+        let extension = &mut self.uiua_extension;
+        thread::scope(|s| {
+            let loading = s.spawn(move || extension.load());
+            while !loading.is_finished() {
+                if let Ok(reply) = requests.recv_timeout(INPUT_REQUEST_POLL_INTERVAL) {
+                    let _ = reply.send(read_line());
+                }
+            }
+            loading
+                .join()
+                .expect("loading main.ua should not have panicked")
+        })?;
+        self.apply_preroll_length()
+    }
+
+    fn apply_preroll_length(&mut self) -> anyhow::Result<()> {
+        let length = self.preroll_length();
+        self.mixer_controller_mut()
+            .set_preroll_length(length)
+            .ok_or(anyhow!("could not set pre-roll length"))
+    }
+
+    fn preroll_length(&self) -> usize {
+        preroll_length(&self.uiua_extension)
     }
 
     fn mixer_controller(&self) -> &MixerController {
@@ -74,22 +165,33 @@ impl Uauauiua {
     }
 
     pub fn reinit_audio(&mut self) {
-        let mut main_recording = self.mixer_controller_mut().get_main_recording();
-        self.partial_main_recording.append(&mut main_recording);
+        // An output file has to be finalized before the new output can append to it
+        self.audio_handler.close();
+
         let mut secondary_recording = self.mixer_controller_mut().get_secondary_recording();
         self.partial_secondary_recording
             .append(&mut secondary_recording);
+        let analyzing_spectrum = self.audio_handler.spectrum_analyzer.is_some();
 
         self.audio_handler = AudioHandler::new(
-            self.mixer_controller().is_recording_main(),
+            &self.output_config,
+            true,
+            self.main_recording.as_ref().map(DiskRecording::sender),
             self.mixer_controller().is_recording_secondary(),
+            self.preroll_length(),
         );
+        self.uiua_extension
+            .connect_mixer(self.audio_handler.mixer_controller());
+        self.audio_handler.analyze_spectrum(analyzing_spectrum);
     }
 
     pub fn start_main_recording(&mut self) -> anyhow::Result<()> {
+        let recording = DiskRecording::start(self.record_stems)?;
         self.mixer_controller_mut()
-            .start_main_recording()
-            .map_err(|_| anyhow!("could not start main recording"))
+            .start_main_recording(recording.sender())
+            .map_err(|_| anyhow!("could not start main recording"))?;
+        self.main_recording = Some(recording);
+        Ok(())
     }
     pub fn start_secondary_recording(&mut self) -> anyhow::Result<()> {
         self.mixer_controller_mut()
@@ -103,29 +205,41 @@ impl Uauauiua {
             .map_err(|_| anyhow!("could not stop playback"))
     }
 
-    pub fn stop_main_recording(&mut self) -> anyhow::Result<Vec<f32>> {
-        let ret = self
-            .mixer_controller_mut()
+    pub fn stop_main_recording(&mut self) -> anyhow::Result<Take> {
+        self.mixer_controller_mut()
             .stop_main_recording()
-            .map_err(|_| anyhow!("could not stop main recording"));
-        ret
+            .map_err(|_| anyhow!("could not stop main recording"))?;
+        self.main_recording
+            .take()
+            .ok_or(anyhow!("no main recording in progress"))?
+            .finish()
     }
 
     pub fn stop_secondary_recording(&mut self) -> anyhow::Result<Vec<f32>> {
-        let ret = self
+        let mut recording = self
             .mixer_controller_mut()
             .stop_secondary_recording()
-            .map_err(|_| anyhow!("could not stop secondary recording"));
-        ret
+            .map_err(|_| anyhow!("could not stop secondary recording"))?;
+        let mut ret = mem::take(&mut self.partial_secondary_recording);
+        ret.append(&mut recording);
+        Ok(ret)
     }
 
-    pub fn add_to_mixer(&mut self, key: KeyCode, toggle_hold: bool) -> anyhow::Result<()> {
-        let key = if let KeyCode::Char(c) = key {
-            KeyCode::Char(c.to_ascii_lowercase())
-        } else {
-            key
-        };
+    /// Returns the last few seconds of output, as configured by `Preroll` in main.ua.
+    pub fn capture_preroll(&mut self) -> anyhow::Result<Vec<f32>> {
+        self.mixer_controller_mut()
+            .capture_preroll()
+            .ok_or(anyhow!("could not capture pre-roll"))
+    }
+    pub fn capture_main_preroll(&mut self) -> anyhow::Result<Take> {
+        Take::from_samples(&self.capture_preroll()?)
+    }
+
+    pub fn recover_recordings(&self) -> anyhow::Result<Vec<PathBuf>> {
+        recover_orphaned_recordings()
+    }
 
+    fn key_source(&self, key: KeyCode) -> anyhow::Result<SamplesBuffer<f32>> {
         let source = self
             .uiua_extension
             .key_sources()
@@ -142,18 +256,80 @@ impl Uauauiua {
             *SAMPLE_RATE
         );
 
-        let source = source.clone();
+        Ok(source.clone())
+    }
+
+    pub fn add_to_mixer(&mut self, key: KeyCode, toggle_hold: bool) -> anyhow::Result<()> {
+        let key = if let KeyCode::Char(c) = key {
+            KeyCode::Char(c.to_ascii_lowercase())
+        } else {
+            key
+        };
+
+        let source = self.key_source(key)?;
+        self.last_triggered.insert(key, Instant::now());
         if toggle_hold {
             self.mixer_controller_mut()
                 .toggle_hold(key, source)
                 .map_err(|_| anyhow!("could not toggle hold for key {key}"))
         } else {
             self.mixer_controller_mut()
-                .add(source)
+                .add(key, source)
                 .map_err(|_| anyhow!("could not play audio for key {key}"))
         }
     }
 
+    /// Plays the key `OnNote` assigns a note to, scaled by the note's velocity.
+    #[cfg(feature = "midi")]
+    pub fn play_note(&mut self, note: u8, velocity: u8) -> anyhow::Result<()> {
+        let key = *self
+            .uiua_extension
+            .note_keys()
+            .get(&note)
+            .ok_or(anyhow!("note {note} not recognized"))?;
+        let source = self.key_source(key)?;
+        self.last_triggered.insert(key, Instant::now());
+        self.mixer_controller_mut()
+            .add_with_gain(key, source, velocity_gain(velocity))
+            .map_err(|_| anyhow!("could not play audio for note {note}"))
+    }
+
+    /// Plays back a performance event log or MIDI file with the sounds currently defined
+    /// in main.ua, optionally making a main recording of it. Keys that are no longer
+    /// defined are skipped, as are notes missing from `OnNote`.
+    pub fn replay(&mut self, path: &Path, record: bool) -> anyhow::Result<()> {
+        let events = if is_midi_file(path) {
+            schedule_notes(
+                read_notes(path, *SAMPLE_RATE)?,
+                self.uiua_extension.note_keys(),
+                |k| self.key_source(k).ok(),
+            )
+        } else {
+            schedule_events(read_events(path, *SAMPLE_RATE)?, |k| {
+                self.key_source(k).ok()
+            })
+        };
+
+        if record && !self.is_recording_main() {
+            self.start_main_recording()?;
+        }
+        self.mixer_controller()
+            .schedule(events)
+            .map_err(|_| anyhow!("could not start replay"))
+    }
+
+    /// Finds the performance log saved with a main recording, or takes a path to a MIDI
+    /// file as is.
+    pub fn replay_path(&self, input: &str) -> anyhow::Result<PathBuf> {
+        ensure!(!input.is_empty(), "nothing to replay given");
+        let path = PathBuf::from(input);
+        if is_midi_file(&path) {
+            Ok(path)
+        } else {
+            Ok(events_path(&resolve_recording_name(input)?))
+        }
+    }
+
     pub fn clear_stack(&mut self) {
         self.uiua_extension.clear_stack();
     }
@@ -162,45 +338,41 @@ impl Uauauiua {
         self.uiua_extension.clear_recordings();
     }
 
-    pub fn save_main_recording(&mut self, recording: &[f32], name: &str) -> anyhow::Result<()> {
-        let mut recording_iter = mem::take(&mut self.partial_main_recording)
-            .into_iter()
-            .chain(recording.iter().copied());
-
-        let spec = WavSpec {
-            channels: CHANNEL_COUNT,
-            sample_rate: *SAMPLE_RATE,
-            bits_per_sample: 32,
-            sample_format: SampleFormat::Float,
-        };
+    /// Validates a name typed for a main recording, using a timestamp if it's empty.
+    pub fn resolve_main_recording_name(&self, name: &str) -> anyhow::Result<String> {
+        resolve_recording_name(name)
+    }
 
-        let _ = fs::create_dir(RECORDINGS_DIR);
+    pub fn main_recording_exists(&self, name: &str, options: ExportOptions) -> bool {
+        recording_exists(name, options)
+    }
 
-        let mut writer = WavWriter::create(format!("{RECORDINGS_DIR}/{name}.wav"), spec)?;
+    pub fn next_free_main_recording_name(&self, name: &str, options: ExportOptions) -> String {
+        next_free_recording_name(name, options)
+    }
 
-        recording_iter.try_for_each(|x| writer.write_sample(x))?;
-        writer.finalize()?;
+    pub fn save_main_recording(
+        &mut self,
+        take: &Take,
+        name: &str,
+        options: ExportOptions,
+    ) -> anyhow::Result<()> {
+        take.save(name, options)
+    }
 
-        Ok(())
+    pub fn discard_main_recording(&mut self, take: &Take) -> anyhow::Result<()> {
+        take.discard()
     }
 
     pub fn save_secondary_recording(&mut self, recording: &[f32], name: &str) {
         let name = if name.is_empty() {
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_micros()
-                .to_string()
+            timestamp()
         } else {
             name.to_string()
         };
 
-        let recording: Vec<f32> = mem::take(&mut self.partial_secondary_recording)
-            .into_iter()
-            .chain(recording.iter().copied())
-            .collect();
         let len = recording.len();
-        let mut recording_array: Array<f64> = recording.into_iter().map(f64::from).collect();
+        let mut recording_array: Array<f64> = recording.iter().copied().map(f64::from).collect();
         recording_array.shape = [len / CHANNEL_COUNT as usize, CHANNEL_COUNT as usize].into();
 
         self.uiua_extension
@@ -211,10 +383,19 @@ impl Uauauiua {
         self.uiua_extension.key_sources().keys().copied().collect()
     }
 
-    pub fn held_sources(&self) -> &IndexSet<KeyCode> {
+    pub fn key_sources(&self) -> &IndexMap<KeyCode, SamplesBuffer<f32>> {
+        self.uiua_extension.key_sources()
+    }
+
+    pub fn held_sources(&self) -> MutexGuard<'_, IndexSet<KeyCode>> {
         self.mixer_controller().held_sources()
     }
 
+    /// When each key was last pressed, held or played by a note. Replays don't count.
+    pub fn last_triggered(&self) -> &HashMap<KeyCode, Instant> {
+        &self.last_triggered
+    }
+
     pub fn secondary_recording_names(&self) -> IndexSet<String> {
         self.uiua_extension.new_value_names()
     }
@@ -223,10 +404,69 @@ impl Uauauiua {
         self.uiua_extension.stack()
     }
 
+    pub fn uiua_log(&self) -> MutexGuard<'_, OutputLog> {
+        self.uiua_extension.log()
+    }
+
+    pub fn shown_images(&self) -> MutexGuard<'_, VecDeque<ShownImage>> {
+        self.uiua_extension.shown_images()
+    }
+
+    /// Sets whether main recordings started from now on also save each key separately.
+    pub fn toggle_stem_recording(&mut self) {
+        self.record_stems = !self.record_stems;
+    }
+    pub fn is_recording_stems(&self) -> bool {
+        self.record_stems
+    }
+
+    /// Explains why audio isn't going where it was meant to, if it isn't.
+    pub fn audio_warning(&self) -> Option<&str> {
+        self.audio_handler.warning.as_deref()
+    }
+
     pub fn is_recording_main(&self) -> bool {
         self.mixer_controller().is_recording_main()
     }
+    pub fn meter(&self) -> &Meter {
+        self.mixer_controller().meter()
+    }
     pub fn is_recording_secondary(&self) -> bool {
         self.mixer_controller().is_recording_secondary()
     }
+    pub fn playback_status(&self) -> PlaybackStatus {
+        self.mixer_controller().playback_status()
+    }
+    /// Starts analyzing the output when the spectrum is shown, and stops when it's hidden.
+    pub fn show_spectrum(&mut self, shown: bool) {
+        self.audio_handler.analyze_spectrum(shown);
+    }
+    /// The spectrum of the output, if it's shown.
+    pub fn spectrum(&self) -> Option<MutexGuard<'_, Spectrum>> {
+        self.audio_handler
+            .spectrum_analyzer
+            .as_ref()
+            .map(SpectrumAnalyzer::spectrum)
+    }
+
+    /// How long the main recording has been going and how big its file is so far.
+    pub fn main_recording_progress(&self) -> Option<(Duration, u64)> {
+        let recording = self.main_recording.as_ref()?;
+        Some((
+            frames_to_duration(recording.frames()),
+            recording.file_size(),
+        ))
+    }
+
+    /// How long the secondary recording has been going, including anything recorded
+    /// before audio was reinitialized.
+    pub fn secondary_recording_duration(&self) -> Option<Duration> {
+        if !self.is_recording_secondary() {
+            return None;
+        }
+        let partial_frames = self.partial_secondary_recording.len() / CHANNEL_COUNT as usize;
+        Some(frames_to_duration(
+            partial_frames as u64 + self.playback_status().secondary_recording_frames,
+        ))
+    }
 }
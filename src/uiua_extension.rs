@@ -1,16 +1,24 @@
-use crate::limited_backend::LimitedBackend;
-use crate::recording::{CHANNEL_COUNT, SAMPLE_RATE};
+use crate::limited_backend::{LimitedBackend, LineRequest, OutputLog, SharedState, ShownImage};
+use crate::recording::{CHANNEL_COUNT, MixerController, SAMPLE_RATE};
 
 use anyhow::{anyhow, bail, ensure};
 use crossterm::event::KeyCode;
 use indexmap::{IndexMap, IndexSet};
 use rodio::buffer::SamplesBuffer;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{MutexGuard, mpsc::Sender};
 use std::time::Duration;
 use uiua::{Boxed, Uiua, Value};
 
 pub const MAIN_PATH: &str = "main.ua";
 const KEY_MAP_NAME: &str = "OnPress";
+const NOTE_MAP_NAME: &str = "OnNote";
+const PREROLL_NAME: &str = "Preroll";
 const EXECUTION_TIME_LIMIT: Duration = Duration::from_secs(5);
+const DEFAULT_PREROLL_DURATION: Duration = Duration::from_secs(30);
+// The mixer keeps the whole pre-roll in memory, so it can't be too long
+const MAX_PREROLL_DURATION: Duration = Duration::from_secs(5 * 60);
 
 fn value_to_source(value: &Value, key: char) -> anyhow::Result<SamplesBuffer<f32>> {
     let value = value.clone().unpacked();
@@ -35,21 +43,21 @@ fn value_to_source(value: &Value, key: char) -> anyhow::Result<SamplesBuffer<f32
     Ok(SamplesBuffer::new(CHANNEL_COUNT, *SAMPLE_RATE, array_vec))
 }
 
-fn get_key_sources(uiua: &mut Uiua) -> anyhow::Result<IndexMap<KeyCode, SamplesBuffer<f32>>> {
-    let vals = uiua.bound_values();
-    let funcs = uiua.bound_functions();
-
-    let owned_map;
-    let map;
-    if let Some(m) = vals.get(KEY_MAP_NAME) {
-        map = m;
-    } else if let Some(f) = funcs.get(KEY_MAP_NAME) {
-        uiua.call(f)?;
-        owned_map = uiua.pop("keyboard map")?;
-        map = &owned_map;
+fn get_binding(uiua: &mut Uiua, name: &str) -> anyhow::Result<Option<Value>> {
+    if let Some(v) = uiua.bound_values().remove(name) {
+        Ok(Some(v))
+    } else if let Some(f) = uiua.bound_functions().remove(name) {
+        uiua.call(&f)?;
+        Ok(Some(uiua.pop(name)?))
     } else {
-        bail!("Could not get {KEY_MAP_NAME}");
+        Ok(None)
     }
+}
+
+fn get_key_sources(uiua: &mut Uiua) -> anyhow::Result<IndexMap<KeyCode, SamplesBuffer<f32>>> {
+    let Some(map) = get_binding(uiua, KEY_MAP_NAME)? else {
+        bail!("Could not get {KEY_MAP_NAME}");
+    };
 
     ensure!(map.is_map(), "{KEY_MAP_NAME} is not a map");
 
@@ -73,24 +81,107 @@ fn get_key_sources(uiua: &mut Uiua) -> anyhow::Result<IndexMap<KeyCode, SamplesB
         .collect()
 }
 
+/// Reads the optional map from MIDI note numbers to keys of the key map.
+fn get_note_keys(
+    uiua: &mut Uiua,
+    key_sources: &IndexMap<KeyCode, SamplesBuffer<f32>>,
+) -> anyhow::Result<IndexMap<u8, KeyCode>> {
+    let Some(map) = get_binding(uiua, NOTE_MAP_NAME)? else {
+        return Ok(IndexMap::new());
+    };
+
+    ensure!(map.is_map(), "{NOTE_MAP_NAME} is not a map");
+
+    map.map_kv()
+        .into_iter()
+        .map(|(k, v)| {
+            let note = k.as_nat(uiua, None)?;
+            let note = u8::try_from(note)
+                .ok()
+                .filter(|&n| n <= 127)
+                .ok_or(anyhow!(
+                    "{NOTE_MAP_NAME} key {note} is not a MIDI note number"
+                ))?;
+            let name = v.as_string(uiua, None)?;
+            let mut chars = name.chars();
+            let key = match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c),
+                _ => bail!("expected {NOTE_MAP_NAME} value for note {note} to be one character"),
+            };
+            ensure!(
+                key_sources.contains_key(&key),
+                "{NOTE_MAP_NAME} value '{key}' for note {note} is not a key of {KEY_MAP_NAME}"
+            );
+            Ok((note, key))
+        })
+        .collect()
+}
+
+fn get_preroll_duration(uiua: &mut Uiua) -> anyhow::Result<Duration> {
+    let Some(value) = get_binding(uiua, PREROLL_NAME)? else {
+        return Ok(DEFAULT_PREROLL_DURATION);
+    };
+
+    let seconds = value.as_num(uiua, None)?;
+    let duration = Duration::try_from_secs_f64(seconds)
+        .map_err(|_| anyhow!("{PREROLL_NAME} must be a non-negative number of seconds"))?;
+    ensure!(
+        duration <= MAX_PREROLL_DURATION,
+        "{PREROLL_NAME} must be at most {} seconds",
+        MAX_PREROLL_DURATION.as_secs()
+    );
+    Ok(duration)
+}
+
 pub struct UiuaExtension {
     uiua: Uiua,
+    shared: SharedState,
     key_sources: IndexMap<KeyCode, SamplesBuffer<f32>>,
+    note_keys: IndexMap<u8, KeyCode>,
+    preroll_duration: Duration,
     recordings: IndexMap<String, Value>,
 }
 
-impl Default for UiuaExtension {
-    fn default() -> Self {
+impl UiuaExtension {
+    /// `allow_git` lets main.ua import git modules, which are downloaded and run.
+    pub fn new(allow_git: bool) -> Self {
+        let shared = SharedState::default();
+        let backend = LimitedBackend::new(allow_git, shared.clone());
         Self {
-            uiua: Uiua::with_backend(LimitedBackend).with_execution_limit(EXECUTION_TIME_LIMIT),
+            uiua: Uiua::with_backend(backend).with_execution_limit(EXECUTION_TIME_LIMIT),
+            shared,
             key_sources: IndexMap::default(),
+            note_keys: IndexMap::default(),
+            preroll_duration: DEFAULT_PREROLL_DURATION,
             recordings: IndexMap::default(),
         }
     }
-}
 
-impl UiuaExtension {
+    /// Sends sounds played with `&ap` and `&ast` to the mixer `controller` controls.
+    pub fn connect_mixer(&self, controller: &MixerController) {
+        *self
+            .shared
+            .mixer
+            .lock()
+            .expect("mixer connection should not be poisoned") = Some(controller.command_sender());
+    }
+
+    /// Sends lines `&sc` asks for to `tx`.
+    pub fn connect_input(&self, tx: Sender<LineRequest>) {
+        *self
+            .shared
+            .input
+            .lock()
+            .expect("input connection should not be poisoned") = Some(tx);
+    }
+
     pub fn load(&mut self) -> anyhow::Result<()> {
+        self.load_file(Path::new(MAIN_PATH))
+    }
+
+    fn load_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.log().clear();
+        self.shown_images().clear();
         let keys: Value = self.recordings.keys().cloned().collect();
         let mut map: Value = self.recordings.values().cloned().map(Boxed).collect();
         map.map(keys, &self.uiua)?;
@@ -100,11 +191,13 @@ impl UiuaExtension {
                 u.push(map.clone());
                 Ok(())
             })?;
-            c.load_file(MAIN_PATH)?;
+            c.load_file(path)?;
             Ok(c)
         })?;
 
         self.key_sources = get_key_sources(&mut self.uiua)?;
+        self.note_keys = get_note_keys(&mut self.uiua, &self.key_sources)?;
+        self.preroll_duration = get_preroll_duration(&mut self.uiua)?;
 
         Ok(())
     }
@@ -113,6 +206,30 @@ impl UiuaExtension {
         &self.key_sources
     }
 
+    pub fn note_keys(&self) -> &IndexMap<u8, KeyCode> {
+        &self.note_keys
+    }
+
+    /// What main.ua has printed since it was last loaded.
+    pub fn log(&self) -> MutexGuard<'_, OutputLog> {
+        self.shared
+            .log
+            .lock()
+            .expect("output log should not be poisoned")
+    }
+
+    /// The images main.ua has shown since it was last loaded, oldest first.
+    pub fn shown_images(&self) -> MutexGuard<'_, VecDeque<ShownImage>> {
+        self.shared
+            .images
+            .lock()
+            .expect("shown images should not be poisoned")
+    }
+
+    pub fn preroll_duration(&self) -> Duration {
+        self.preroll_duration
+    }
+
     pub fn new_value_names(&self) -> IndexSet<String> {
         self.recordings.keys().cloned().collect()
     }
@@ -133,3 +250,59 @@ impl UiuaExtension {
         self.recordings.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    /// Describes what a script loaded into, or why it didn't.
+    fn summarize(path: &Path) -> String {
+        let mut extension = UiuaExtension::new(false);
+        if let Err(e) = extension.load_file(path) {
+            return format!("error: {e}\n");
+        }
+
+        let mut summary = format!("preroll {:?}\n", extension.preroll_duration());
+        for (key, source) in extension.key_sources() {
+            let samples: Vec<f32> = source.clone().collect();
+            let peak = samples.iter().fold(0.0_f32, |acc, x| acc.max(x.abs()));
+            summary += &format!(
+                "{key} {} frames, peak {peak}\n",
+                samples.len() / CHANNEL_COUNT as usize
+            );
+        }
+        for (note, key) in extension.note_keys() {
+            summary += &format!("note {note} {key}\n");
+        }
+        summary
+    }
+
+    /// Loads every script in tests/scripts and compares the result with the .expected
+    /// file beside it. Set `UPDATE_GOLDEN` to rewrite the .expected files instead.
+    #[test]
+    fn scripts_match_golden_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+        let update = env::var_os("UPDATE_GOLDEN").is_some();
+
+        let mut scripts: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "ua"))
+            .collect();
+        scripts.sort();
+        assert!(!scripts.is_empty(), "no scripts in {}", dir.display());
+
+        for script in scripts {
+            let summary = summarize(&script);
+            let expected_path = script.with_extension("expected");
+            if update {
+                fs::write(&expected_path, &summary).unwrap();
+            } else {
+                let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+                assert_eq!(summary, expected, "{}", script.display());
+            }
+        }
+    }
+}